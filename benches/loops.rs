@@ -0,0 +1,111 @@
+//! Times a few loop-heavy programs. Run with `cargo bench`.
+//!
+//! To see what a change does to the times, save them before making it with
+//! `cargo bench --bench loops -- --save before.txt` and compare against them
+//! afterwards with `cargo bench --bench loops -- --baseline before.txt`. Only
+//! the public API is used, so this file can also be copied into an older
+//! revision to save its times. Programs an older revision can't run are
+//! skipped.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{empty, sink};
+use std::time::{Duration, Instant};
+
+use stalch::{compile, InOuter, State};
+
+const STDLIB: &str = include_str!("../examples/stdlib.stalch");
+
+const FIZZBUZZ: &str = r#"
+{
+    "" swap
+    dup 3 % 0 == { swap "Fizz" + swap } {} if ()
+    dup 5 % 0 == { swap "Buzz" + swap } {} if ()
+    swap dup { _ drop } { drop _ } if ()
+}
+20000 forrange ()
+"#;
+
+const COUNT: &str = "0 { 1 + dup 2 % drop } 60000 * () drop";
+
+const NESTED: &str = "0 { { 1 + dup 2 % drop } 60000 * () } 30 * () drop";
+
+const BUILD_LIST: &str = "( ) { ( 1 ) + } 20000 times drop";
+
+const SUM_BLOCK: &str = "{ 1 2 3 4 5 6 7 8 9 10 } 5000 * sumBlock () drop";
+
+fn dup_block() -> String {
+    let big: Vec<_> = (0..5000).map(|i| i.to_string()).collect();
+    format!("{{ {} }} big := {{ big dup drop drop }} 10000 * ()", big.join(" "))
+}
+
+/// The average time of a run in milliseconds, unless the program fails
+fn bench(src: &str, runs: u32) -> Option<f64> {
+    let src = format!("{}\n{}", STDLIB, src);
+    let program = compile(src.as_bytes()).ok()?;
+    let mut io = InOuter::new(sink(), empty());
+
+    let mut total = Duration::default();
+    for _ in 0..runs {
+        let mut state = State::new();
+        let start = Instant::now();
+        if program.run(&mut state, &mut io).is_err() {
+            return None;
+        }
+        total += start.elapsed();
+    }
+    Some(total.as_secs_f64() * 1000. / f64::from(runs))
+}
+
+/// The file given after the option, if it is given
+fn option(name: &str) -> Option<String> {
+    let mut args = env::args().skip_while(|arg| arg != name);
+    args.next()?;
+    args.next()
+}
+
+fn main() {
+    let save = option("--save");
+    let baseline: HashMap<String, f64> = match option("--baseline") {
+        Some(path) => {
+            let saved = fs::read_to_string(&path).expect("cannot read the baseline");
+            saved
+                .lines()
+                .filter_map(|line| {
+                    let mut words = line.split_whitespace();
+                    Some((words.next()?.to_owned(), words.next()?.parse().ok()?))
+                })
+                .collect()
+        }
+        None => HashMap::new(),
+    };
+    let dup_block = dup_block();
+    let programs = [
+        ("fizzbuzz", FIZZBUZZ, 20),
+        ("count", COUNT, 20),
+        ("nested", NESTED, 5),
+        ("buildlist", BUILD_LIST, 20),
+        ("sumblock", SUM_BLOCK, 20),
+        ("dupblock", &dup_block, 5),
+    ];
+
+    let mut saved = String::new();
+    for &(name, src, runs) in &programs {
+        let time = match bench(src, runs) {
+            Some(time) => time,
+            None => {
+                println!("{:<10} can't be run", name);
+                continue;
+            }
+        };
+        saved.push_str(&format!("{} {}\n", name, time));
+        match baseline.get(name) {
+            Some(before) => println!("{:<10} {:>10.3} ms/run, was {:>10.3} ms/run, {:>5.2}x as fast", name, time, before, before / time),
+            None => println!("{:<10} {:>10.3} ms/run", name, time),
+        }
+    }
+    if let Some(path) = save {
+        fs::write(&path, saved).expect("cannot save the times");
+    }
+}
@@ -3,6 +3,24 @@ pub enum Command {
     Value(Val),
     BeginBlock,
     EndBlock,
+    /// A block literal made of the next `n` commands.
+    ///
+    /// `{` and `}` are turned into this when parsing, so running it just
+    /// pushes the block and jumps past it.
+    Quote(usize),
+    BeginClosure,
+    /// A closure literal made of the next `n` commands.
+    ///
+    /// Like `Quote`, but the block also gets the current values of the
+    /// variables it uses.
+    Capture(usize),
+    BeginList,
+    EndList,
+    /// A list literal made of the next `n` commands.
+    ///
+    /// `(` and `)` are turned into this when parsing. The commands are run
+    /// and everything they leave on the stack is collected into the list.
+    Collect(usize),
     Pack,
     Size,
     Length,
@@ -10,6 +28,7 @@ pub enum Command {
     Not,
     If,
     Define,
+    Local,
     ApplyFunction,
     Read,
     Swap,
@@ -25,6 +44,15 @@ pub enum Command {
     ToFloat,
     ToInt,
     ToBool,
+    ToList,
+    ToBlock,
+    ToMap,
+    Insert,
+    Lookup,
+    Remove,
+    Keys,
+    Has,
+    Pragma,
     Eq,
     Neq,
     GreaterThan,
@@ -34,6 +62,15 @@ pub enum Command {
     Write,
     Print,
     Exit,
+    Return,
+    Try,
+    Throw,
+    While,
+    Times,
+    Each,
+    Cond,
+    Break,
+    Continue,
     Or,
     And,
     Xor,
@@ -47,10 +84,29 @@ pub enum Command {
 
 impl Debug for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", match self {
-            Value(v) => return v.fmt(f),
+        match self {
+            Value(v) => v.fmt(f),
+            Quote(n) => write!(f, "{{{}}}", n),
+            Capture(n) => write!(f, "^{{{}}}", n),
+            Collect(n) => write!(f, "({})", n),
+            cmd => write!(f, "{}", cmd.name()),
+        }
+    }
+}
+
+impl Command {
+    /// The name the command is shown with, with literals named after what they make
+    pub fn name(&self) -> &'static str {
+        match self {
+            Value(_) => "<value>",
             BeginBlock => "{",
             EndBlock => "}",
+            Quote(_) => "<block>",
+            BeginClosure => "^{",
+            Capture(_) => "<closure>",
+            BeginList => "(",
+            EndList => ")",
+            Collect(_) => "<list>",
             Pack => "@",
             Size => "size",
             Length => "len",
@@ -58,6 +114,7 @@ impl Debug for Command {
             Not => "!",
             If => "if",
             Define => ":=",
+            Local => "local",
             ApplyFunction => "()",
             Read => "<-",
             Swap => "$",
@@ -73,6 +130,15 @@ impl Debug for Command {
             ToFloat => "float",
             ToInt => "int",
             ToBool => "bool",
+            ToList => "list",
+            ToBlock => "block",
+            ToMap => "map",
+            Insert => "insert",
+            Lookup => "lookup",
+            Remove => "remove",
+            Keys => "keys",
+            Has => "has",
+            Pragma => "pragma",
             Eq => "==",
             Neq => "!=",
             GreaterThan => ">",
@@ -82,6 +148,15 @@ impl Debug for Command {
             Write => "->",
             Print => "_",
             Exit => "x",
+            Return => "return",
+            Try => "try",
+            Throw => "throw",
+            While => "while",
+            Times => "times",
+            Each => "each",
+            Cond => "cond",
+            Break => "break",
+            Continue => "continue",
             Or => "|",
             And => "&",
             Xor => "^",
@@ -91,7 +166,7 @@ impl Debug for Command {
             Pow => "**",
             Div => "/",
             Rem => "%",
-        })
+        }
     }
 }
 
@@ -108,50 +183,118 @@ impl Command {
         }
     }
     pub fn from_str_pure(cmd: &str) -> Option<Self> {
-        Some(match &*cmd.to_lowercase() {
-            "{" | "[" => BeginBlock,
-            "}" | "]" => EndBlock,
-            "inc" | "include" => Include,
-            "@" | "pack" => Pack,
-            "size" => Size,
-            "len" => Length,
-            "d" | "dup" => Dup,
-            "!" | "not" => Not,
-            "?" | "if" => If,
-            ":=" | "def" => Define,
-            "()" | "apply" => ApplyFunction,
-            "<-" | "read" => Read,
-            "$" | "swap" => Swap,
-            "\\/" | "\\\\/" | "split" => Split,
-            "." | "get" => Get,
-            ";" | "dupget" => DupGet,
-            "<>" | "move" => Move,
-            "#" | "grab" => Grab,
-            ":" | "dupgrab" => DupGrab,
-            "~" | "drop" => Drop,
-            "t" | "type" => Type,
-            "f" | "float" => ToFloat,
-            "i" | "int" => ToInt,
-            "b" | "bool" => ToBool,
-            "==" | "eq" => Eq,
-            "!=" | "neq" => Neq,
-            ">" => GreaterThan,
-            ">=" => GreaterEquals,
-            "<" => LessThan,
-            "<=" => LessEquals,
-            "->" | "wrte" => Write,
-            "_" | "prnt" => Print,
-            "x" | "exit" => Exit,
-            "|" | "or" => Or,
-            "&" | "and" => And,
-            "^" | "xor" => Xor,
-            "+" | "add" => Add,
-            "-" | "sub" => Sub,
-            "*" | "mul" => Mul,
-            "**" | "pow" => Pow,
-            "/" | "div" => Div,
-            "%" | "rem" => Rem,
-            _ => return None,
-        })
+        let cmd = cmd.to_lowercase();
+        NAMES.iter().find(|&&(name, _)| name == cmd).map(|(_, cmd)| cmd.clone())
     }
 }
+
+/// Every name and alias of the commands, which is what the parser goes by
+const NAMES: &[(&str, Command)] = &[
+    ("{", BeginBlock),
+    ("[", BeginBlock),
+    ("}", EndBlock),
+    ("]", EndBlock),
+    ("^{", BeginClosure),
+    ("^[", BeginClosure),
+    ("(", BeginList),
+    (")", EndList),
+    ("inc", Include),
+    ("include", Include),
+    ("@", Pack),
+    ("pack", Pack),
+    ("size", Size),
+    ("len", Length),
+    ("d", Dup),
+    ("dup", Dup),
+    ("!", Not),
+    ("not", Not),
+    ("?", If),
+    ("if", If),
+    (":=", Define),
+    ("def", Define),
+    ("local", Local),
+    ("()", ApplyFunction),
+    ("apply", ApplyFunction),
+    ("<-", Read),
+    ("read", Read),
+    ("$", Swap),
+    ("swap", Swap),
+    ("\\/", Split),
+    ("\\\\/", Split),
+    ("split", Split),
+    (".", Get),
+    ("get", Get),
+    (";", DupGet),
+    ("dupget", DupGet),
+    ("<>", Move),
+    ("move", Move),
+    ("#", Grab),
+    ("grab", Grab),
+    (":", DupGrab),
+    ("dupgrab", DupGrab),
+    ("~", Drop),
+    ("drop", Drop),
+    ("t", Type),
+    ("type", Type),
+    ("f", ToFloat),
+    ("float", ToFloat),
+    ("i", ToInt),
+    ("int", ToInt),
+    ("b", ToBool),
+    ("bool", ToBool),
+    ("list", ToList),
+    ("block", ToBlock),
+    ("map", ToMap),
+    ("insert", Insert),
+    ("lookup", Lookup),
+    ("remove", Remove),
+    ("keys", Keys),
+    ("has", Has),
+    ("pragma", Pragma),
+    ("==", Eq),
+    ("eq", Eq),
+    ("!=", Neq),
+    ("neq", Neq),
+    (">", GreaterThan),
+    (">=", GreaterEquals),
+    ("<", LessThan),
+    ("<=", LessEquals),
+    ("->", Write),
+    ("wrte", Write),
+    ("_", Print),
+    ("prnt", Print),
+    ("x", Exit),
+    ("exit", Exit),
+    ("return", Return),
+    ("try", Try),
+    ("throw", Throw),
+    ("while", While),
+    ("times", Times),
+    ("each", Each),
+    ("cond", Cond),
+    ("break", Break),
+    ("continue", Continue),
+    ("|", Or),
+    ("or", Or),
+    ("&", And),
+    ("and", And),
+    ("^", Xor),
+    ("xor", Xor),
+    ("+", Add),
+    ("add", Add),
+    ("-", Sub),
+    ("sub", Sub),
+    ("*", Mul),
+    ("mul", Mul),
+    ("**", Pow),
+    ("pow", Pow),
+    ("/", Div),
+    ("div", Div),
+    ("%", Rem),
+    ("rem", Rem),
+];
+
+/// The names and aliases of every command
+pub fn command_names() -> impl Iterator<Item = &'static str> {
+    NAMES.iter().map(|&(name, _)| name)
+}
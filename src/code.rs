@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::cmd::Command::{self, Capture, Collect, Quote};
+use crate::span::Spanned;
+use crate::value::{fmt_list, Value};
+use crate::var::Var;
+
+/// Variables captured by a closure.
+///
+/// They're shared by every copy of the closure, so changes made to them while
+/// it runs are seen the next time it's applied.
+#[derive(Clone)]
+pub struct Env(Arc<Mutex<Vec<(Var, Value)>>>);
+
+impl Env {
+    /// The variables, which nothing else can use until they're given back
+    pub fn lock(&self) -> MutexGuard<'_, Vec<(Var, Value)>> {
+        // Each variable is replaced as a whole, so a panic while holding them
+        // can't leave any of them half changed
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Copies of the variables of closures, so copied values don't share them with
+/// the values they were copied from, while closures that shared them before
+/// still share the copies
+#[derive(Default)]
+pub(crate) struct EnvCopies(HashMap<*const Mutex<Vec<(Var, Value)>>, Env>);
+
+impl EnvCopies {
+    pub fn value(&mut self, value: &mut Value) {
+        match value {
+            Value::Block(_, code) => self.code(code),
+            Value::List(l) => l.iter_mut().for_each(|v| self.value(v)),
+            Value::Map(m) => m.values_mut().for_each(|v| self.value(v)),
+            _ => (),
+        }
+    }
+    pub fn code(&mut self, code: &mut Code) {
+        if let Some(env) = code.env.take() {
+            code.env = Some(self.env(&env));
+        }
+        // Closures only end up in the commands when a list is made into a block
+        let nested = |cmd: &Spanned<Command>| matches!(cmd.inner, Command::Value(ref v) if v.is_collection());
+        if code.cmds.iter().any(nested) {
+            let mut cmds = (*code.cmds).clone();
+            for cmd in &mut cmds {
+                if let Command::Value(ref mut v) = cmd.inner {
+                    self.value(v);
+                }
+            }
+            code.cmds = Arc::new(cmds);
+        }
+    }
+    fn env(&mut self, env: &Env) -> Env {
+        if let Some(copy) = self.0.get(&Arc::as_ptr(&env.0)) {
+            return copy.clone();
+        }
+        // Added before copying the variables, as they can hold the closure itself
+        let copy = Env(Arc::new(Mutex::new(Vec::new())));
+        self.0.insert(Arc::as_ptr(&env.0), copy.clone());
+        let mut vars = env.lock().clone();
+        for (_, v) in &mut vars {
+            self.value(v);
+        }
+        *copy.lock() = vars;
+        copy
+    }
+}
+
+/// The commands of a block or program.
+///
+/// Cloning only bumps a reference count and taking a part of it shares the
+/// same commands, so the commands are only copied when modified.
+#[derive(Clone, Default)]
+pub struct Code {
+    cmds: Arc<Vec<Spanned<Command>>>,
+    start: usize,
+    end: usize,
+    env: Option<Env>,
+}
+
+impl Code {
+    /// A part of this code sharing the same commands, without any captured variables
+    pub fn slice(&self, start: usize, end: usize) -> Self {
+        assert!(start <= end && end <= self.len());
+        Code {
+            cmds: self.cmds.clone(),
+            start: self.start + start,
+            end: self.start + end,
+            env: None,
+        }
+    }
+    /// The variables captured if this is the code of a closure
+    #[inline]
+    pub fn env(&self) -> Option<&Env> {
+        self.env.as_ref()
+    }
+    /// Turns the code into a closure with the given captured variables
+    pub fn with_env(mut self, env: Vec<(Var, Value)>) -> Self {
+        self.env = Some(Env(Arc::new(Mutex::new(env))));
+        self
+    }
+    /// The variables used anywhere in the code, including nested blocks
+    pub fn variables(&self) -> Vec<Var> {
+        let mut vars = Vec::new();
+        for cmd in self.iter() {
+            if let Command::Value(Value::Variable(var)) = cmd.inner {
+                if !vars.contains(&var) {
+                    vars.push(var);
+                }
+            }
+        }
+        vars
+    }
+    /// Takes out the commands to modify them, only copying them if they're shared
+    pub fn into_vec(self) -> Vec<Spanned<Command>> {
+        if self.start == 0 && self.end == self.cmds.len() {
+            Arc::try_unwrap(self.cmds).unwrap_or_else(|cmds| (*cmds).clone())
+        } else {
+            self.to_vec()
+        }
+    }
+    /// Every element of the code as its own piece of code
+    pub fn pieces(&self) -> impl Iterator<Item = Code> + '_ {
+        let mut start = 0;
+        elements(self).map(move |elem| {
+            let piece = self.slice(start, start + elem.len());
+            start += elem.len();
+            piece
+        })
+    }
+}
+
+impl From<Vec<Spanned<Command>>> for Code {
+    fn from(cmds: Vec<Spanned<Command>>) -> Self {
+        Code {
+            end: cmds.len(),
+            cmds: Arc::new(cmds),
+            start: 0,
+            env: None,
+        }
+    }
+}
+
+impl Deref for Code {
+    type Target = [Spanned<Command>];
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        &self.cmds[self.start..self.end]
+    }
+}
+
+impl PartialEq for Code {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Debug for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.env.is_some() {
+            write!(f, "^")?;
+        }
+        f.debug_set().entries(elements(self).map(ShowElement)).finish()
+    }
+}
+
+/// The parts of some code where a block, closure or list literal counts as one element
+pub fn elements(code: &[Spanned<Command>]) -> Elements<'_> {
+    Elements(code)
+}
+
+pub struct Elements<'a>(&'a [Spanned<Command>]);
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = &'a [Spanned<Command>];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = match self.0.first()?.inner {
+            Quote(n) | Capture(n) | Collect(n) => n + 1,
+            _ => 1,
+        };
+        let (elem, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(elem)
+    }
+}
+
+/// Where in the code the `n`th element counting from the back starts and ends
+pub fn element_back(code: &[Spanned<Command>], n: usize) -> Option<(usize, usize)> {
+    let lens: Vec<_> = elements(code).map(<[_]>::len).collect();
+    let i = lens.len().checked_sub(n.checked_add(1)?)?;
+    let start = lens[..i].iter().sum();
+
+    Some((start, start + lens[i]))
+}
+
+/// Where to split the code so that the last `n` elements end up on the right
+pub fn split_point_back(code: &[Spanned<Command>], n: usize) -> Option<usize> {
+    let lens: Vec<_> = elements(code).map(<[_]>::len).collect();
+    let i = lens.len().checked_sub(n)?;
+
+    Some(lens[..i].iter().sum())
+}
+
+/// Debug formats an element of some code, showing block and list literals nested
+pub struct ShowElement<'a>(pub &'a [Spanned<Command>]);
+
+impl Debug for ShowElement<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0[0].inner {
+            Quote(_) => f.debug_set().entries(elements(&self.0[1..]).map(ShowElement)).finish(),
+            Capture(_) => {
+                write!(f, "^")?;
+                f.debug_set().entries(elements(&self.0[1..]).map(ShowElement)).finish()
+            }
+            Collect(_) => fmt_list(f, elements(&self.0[1..]).map(ShowElement)),
+            _ => self.0[0].fmt(f),
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+use stalch::*;
+
+use crate::source_line;
+
+const HELP: &str = "\
+s, step          run the next command
+n, next          run the next command, stepping over blocks it applies
+o, out           run until the block being run is done
+c, continue      run until a breakpoint is reached
+b, break <at>    pause at a line number, optionally after a file and a colon like stdlib.stalch:12,
+                 or when a variable with that name is applied
+d, delete <at>   remove a breakpoint
+b, break         list the breakpoints
+st, stack        show the stack
+v, vars          show the defined variables
+p, print <name>  show the value of a variable
+q, quit          stop the program
+An empty line repeats the last command";
+
+/// Where the debugger can be told to pause
+#[derive(PartialEq, Eq)]
+enum Breakpoint {
+    /// A line of a file, which is the program being debugged unless given
+    Line(String, u32),
+    Apply(String),
+}
+
+impl Breakpoint {
+    fn parse(s: &str, main: &str) -> Self {
+        let (file, line) = s.rsplit_once(':').unwrap_or((main, s));
+        match line.parse() {
+            Ok(line) => Breakpoint::Line(file.to_owned(), line),
+            Err(_) => Breakpoint::Apply(s.to_owned()),
+        }
+    }
+}
+
+/// Pauses the program to let the user step through it, for `--debug`
+pub struct Debugger {
+    /// The file of the program being debugged
+    main: String,
+    /// Pause at the next command that is at most this deep
+    until: Option<usize>,
+    breakpoints: Vec<Breakpoint>,
+    /// The file and line of the last command, so line breakpoints only pause
+    /// when the line is entered
+    last_line: Option<(Arc<str>, u32)>,
+    /// The command to repeat when an empty line is entered
+    last_input: String,
+}
+
+impl Debugger {
+    /// A debugger that pauses before the first command of the program in the file
+    pub fn new(main: &str) -> Self {
+        Debugger {
+            main: main.to_owned(),
+            until: Some(usize::MAX),
+            breakpoints: Vec::new(),
+            last_line: None,
+            last_input: String::new(),
+        }
+    }
+    fn at_breakpoint(&self, step: &Step<'_>, state: &State, entered: bool) -> bool {
+        let applied = step.applied(state);
+        self.breakpoints.iter().any(|b| match b {
+            Breakpoint::Line(file, line) => {
+                // Included files can be given by the end of the path they were included with
+                entered && step.span().is_some_and(|span| span.line == *line && Path::new(&*span.file).ends_with(file))
+            }
+            Breakpoint::Apply(name) => applied.as_deref() == Some(name.as_str()),
+        })
+    }
+    /// Whether to pause before the command
+    fn pauses(&mut self, step: &Step<'_>, state: &State) -> bool {
+        let line = step.span().map(|span| (span.file.clone(), span.line));
+        let entered = line != self.last_line;
+        self.last_line = line;
+        let stepped = self.until.is_some_and(|depth| step.depth() <= depth);
+        stepped || self.at_breakpoint(step, state, entered)
+    }
+    /// Runs a command given at the prompt, returning whether the program should go on
+    fn command(&mut self, input: &str, step: &Step<'_>, state: &State) -> bool {
+        let mut words = input.split_whitespace();
+        let cmd = words.next().unwrap_or("");
+        let arg = words.next();
+        match (cmd, arg) {
+            ("s", _) | ("step", _) => self.until = Some(usize::MAX),
+            ("n", _) | ("next", _) => self.until = Some(step.depth()),
+            ("o", _) | ("out", _) => self.until = Some(step.depth() - 1),
+            ("c", _) | ("continue", _) => self.until = None,
+            ("b", Some(at)) | ("break", Some(at)) => {
+                self.breakpoints.push(Breakpoint::parse(at, &self.main));
+                return false;
+            }
+            ("b", None) | ("break", None) => {
+                for b in &self.breakpoints {
+                    match b {
+                        Breakpoint::Line(file, line) => eprintln!("{}:{}", file, line),
+                        Breakpoint::Apply(name) => eprintln!("{} ()", name),
+                    }
+                }
+                return false;
+            }
+            ("d", Some(at)) | ("delete", Some(at)) => {
+                let b = Breakpoint::parse(at, &self.main);
+                self.breakpoints.retain(|other| *other != b);
+                return false;
+            }
+            ("st", _) | ("stack", _) => {
+                eprintln!("{:?}", state.show_stack());
+                return false;
+            }
+            ("v", _) | ("vars", _) => {
+                for (name, v) in state.vars() {
+                    eprintln!("{} = {:?}", name, v);
+                }
+                return false;
+            }
+            ("p", Some(name)) | ("print", Some(name)) => {
+                match state.lookup(name) {
+                    Some(v) => eprintln!("{:?}", v),
+                    None => eprintln!("{} is not defined", name),
+                }
+                return false;
+            }
+            ("q", _) | ("quit", _) => process::exit(0),
+            _ => {
+                eprintln!("{}", HELP);
+                return false;
+            }
+        }
+        true
+    }
+    fn show(&self, step: &Step<'_>) {
+        match step.span() {
+            Some(span) => {
+                eprintln!("{}: {}", span, step);
+                if let Some(line) = source_line(span, &[]) {
+                    eprintln!(" {} | {}", span.line, line);
+                }
+            }
+            None => eprintln!("{}", step),
+        }
+    }
+}
+
+impl Tracer for Debugger {
+    fn before_command(&mut self, step: &Step<'_>, state: &State) {
+        if !self.pauses(step, state) {
+            return;
+        }
+        self.show(step);
+
+        let stdin = io::stdin();
+        loop {
+            eprint!("(debug) ");
+            let _ = io::stderr().flush();
+            let mut input = String::new();
+            match stdin.lock().read_line(&mut input) {
+                // Without anyone to give commands, the program just runs to its end
+                Ok(0) | Err(_) => {
+                    eprintln!();
+                    self.until = None;
+                    self.breakpoints.clear();
+                    return;
+                }
+                Ok(_) => (),
+            }
+            if input.trim().is_empty() {
+                input = self.last_input.clone();
+            } else {
+                self.last_input = input.trim().to_owned();
+            }
+            if self.command(&input, step, state) {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Breakpoint, Debugger};
+    use stalch::{compile_named, InOuter, State, Step, Tracer};
+
+    /// Gives the debugger a command each time it pauses, returning the line
+    /// and command it paused at
+    fn pauses(commands: &[&str]) -> Vec<String> {
+        struct Script<'a> {
+            debugger: Debugger,
+            commands: &'a [&'a str],
+            paused: Vec<String>,
+        }
+        impl Tracer for Script<'_> {
+            fn before_command(&mut self, step: &Step<'_>, state: &State) {
+                if self.debugger.pauses(step, state) {
+                    self.paused.push(format!("{} {}", step.span().unwrap().line, step));
+                    while let Some((cmd, rest)) = self.commands.split_first() {
+                        self.commands = rest;
+                        if self.debugger.command(cmd, step, state) {
+                            return;
+                        }
+                    }
+                    self.debugger.command("c", step, state);
+                }
+            }
+        }
+        let src = "{ 1 +\n  2 * } bump :=\n1 bump ()\nbump () ~";
+        let program = compile_named("main.stalch", src.as_bytes()).unwrap();
+        let mut script = Script { debugger: Debugger::new("main.stalch"), commands, paused: Vec::new() };
+        program.run_traced(&mut State::new(), &mut InOuter::new(Vec::new(), &b""[..]), &mut script).unwrap();
+        script.paused
+    }
+
+    #[test]
+    fn breakpoints() {
+        assert!(Breakpoint::parse("12", "main.stalch") == Breakpoint::Line("main.stalch".to_owned(), 12));
+        assert!(Breakpoint::parse("lib/stdlib.stalch:3", "main.stalch") == Breakpoint::Line("lib/stdlib.stalch".to_owned(), 3));
+        assert!(Breakpoint::parse("bump", "main.stalch") == Breakpoint::Apply("bump".to_owned()));
+
+        assert_eq!(pauses(&["b 3", "c"]), ["1 {1, +, 2, *}", "3 1"]);
+        assert_eq!(pauses(&["b main.stalch:2", "c", "c"]), ["1 {1, +, 2, *}", "2 bump", "2 2", "2 2"]);
+        assert_eq!(pauses(&["b other.stalch:3", "c"]), ["1 {1, +, 2, *}"]);
+        assert_eq!(pauses(&["b bump", "c", "c"]), ["1 {1, +, 2, *}", "3 ()", "4 ()"]);
+        assert_eq!(pauses(&["b bump", "d bump", "c"]), ["1 {1, +, 2, *}"]);
+    }
+
+    #[test]
+    fn stepping() {
+        assert_eq!(pauses(&["s", "s", "s", "s", "s", "s"]).len(), 7);
+        let top = ["1 {1, +, 2, *}", "2 bump", "2 :=", "3 1", "3 bump", "3 ()"];
+        assert_eq!(pauses(&["n", "n", "n", "n", "n", "n", "c"]), [&top[..], &["4 bump"]].concat());
+        assert_eq!(pauses(&["n", "n", "n", "n", "n", "s", "s", "o", "c"]), [&top[..], &["1 1", "1 +", "4 bump"]].concat());
+    }
+}
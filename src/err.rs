@@ -1,14 +1,18 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
 use std::io::Error as IoError;
 use std::result::Result as StdResult;
 
 use crate::chars::CharsError;
+use crate::limits::Limit;
+use crate::permissions::Capability;
+use crate::span::Span;
+use crate::value::{Key, Value};
 
-#[must_use]
 pub type Result<T> = StdResult<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
-    Exit,
     EmptyStack,
     OutOfBounds,
     InvalidAssignArg,
@@ -18,9 +22,217 @@ pub enum Error {
     InvalidGetArg,
     InvalidMoveArg,
     InvalidGrabArg,
+    InvalidExitCode,
+    InvalidWhileArg,
+    InvalidTimesArg,
+    InvalidEachArg,
+    InvalidCondArg,
+    InvalidTryArg,
+    /// `break` or `continue` was used outside of a loop
+    NoLoop,
+    InvalidMapArg,
+    InvalidKey,
+    /// An operator was used on values of types it doesn't work on
+    TypeError {
+        op: &'static str,
+        lhs_type: &'static str,
+        rhs_type: Option<&'static str>,
+    },
+    DivisionByZero,
+    /// Something was multiplied by a negative number of times
+    NegativeRepeat,
+    /// Something was repeated more times than can be handled
+    RepeatOverflow,
+    /// A variable was used before being defined, only an error in strict mode
+    UndefinedVariable(String),
+    /// A value couldn't be cast to another type, only an error in strict mode
+    InvalidCast {
+        from: &'static str,
+        to: &'static str,
+    },
+    UnknownPragma(String),
+    /// One of the limits set with `State::set_limits` was exceeded, which
+    /// `try` can't catch
+    LimitExceeded(Limit),
+    /// The program tried to do something `State::set_permissions` doesn't allow
+    PermissionDenied(Capability),
+    /// An error raised by `throw`
+    Thrown {
+        kind: String,
+        message: String,
+    },
     NoBlockStarted,
+    UnclosedBlock,
+    NoListStarted,
+    UnclosedList,
+    UnclosedString,
+    ListUnderflow,
     IoError(IoError),
     CharsError(CharsError),
+    /// The error was caused by the command at the given location
+    At(Span, Box<Error>),
+    /// The error happened in a file included by the `inc` command at the given location
+    Included(Span, Box<Error>),
+}
+
+impl Error {
+    /// A `TypeError` for the operator used on the given operands
+    pub(crate) fn type_error(op: &'static str, lhs: &Value, rhs: Option<&Value>) -> Self {
+        Error::TypeError {
+            op,
+            lhs_type: lhs.type_name(),
+            rhs_type: rhs.map(Value::type_name),
+        }
+    }
+    /// The name of the kind of error, as given to `try` handlers
+    pub fn name(&self) -> &str {
+        use self::Error::*;
+        match self.kind() {
+            EmptyStack => "EmptyStack",
+            OutOfBounds => "OutOfBounds",
+            InvalidAssignArg => "InvalidAssignArg",
+            InvalidIncludeArg => "InvalidIncludeArg",
+            InvalidApplyArg => "InvalidApplyArg",
+            InvalidSplitArg => "InvalidSplitArg",
+            InvalidGetArg => "InvalidGetArg",
+            InvalidMoveArg => "InvalidMoveArg",
+            InvalidGrabArg => "InvalidGrabArg",
+            InvalidExitCode => "InvalidExitCode",
+            InvalidWhileArg => "InvalidWhileArg",
+            InvalidTimesArg => "InvalidTimesArg",
+            InvalidEachArg => "InvalidEachArg",
+            InvalidCondArg => "InvalidCondArg",
+            InvalidTryArg => "InvalidTryArg",
+            NoLoop => "NoLoop",
+            InvalidMapArg => "InvalidMapArg",
+            InvalidKey => "InvalidKey",
+            TypeError { .. } => "TypeError",
+            DivisionByZero => "DivisionByZero",
+            NegativeRepeat => "NegativeRepeat",
+            RepeatOverflow => "RepeatOverflow",
+            UndefinedVariable(_) => "UndefinedVariable",
+            InvalidCast { .. } => "InvalidCast",
+            UnknownPragma(_) => "UnknownPragma",
+            LimitExceeded(_) => "LimitExceeded",
+            PermissionDenied(_) => "PermissionDenied",
+            Thrown { kind, .. } => kind,
+            NoBlockStarted => "NoBlockStarted",
+            UnclosedBlock => "UnclosedBlock",
+            NoListStarted => "NoListStarted",
+            UnclosedList => "UnclosedList",
+            UnclosedString => "UnclosedString",
+            ListUnderflow => "ListUnderflow",
+            IoError(_) => "IoError",
+            CharsError(_) => "CharsError",
+            At(_, _) | Included(_, _) => unreachable!("kind has no location"),
+        }
+    }
+    /// The error as a map of its kind and message, as given to `try` handlers
+    pub(crate) fn value(&self) -> Value {
+        let mut map = BTreeMap::new();
+        map.insert(Key::Str("kind".to_owned()), Value::Str(self.name().to_owned()));
+        map.insert(Key::Str("message".to_owned()), Value::Str(self.to_string()));
+        Value::Map(map)
+    }
+    /// Attaches the location of the command that caused this error,
+    /// unless it already knows where it happened
+    pub fn at(self, span: Option<&Span>) -> Self {
+        match (self, span) {
+            (e @ Error::At(_, _), _) | (e @ Error::Included(_, _), _) | (e, None) => e,
+            (e, Some(span)) => Error::At(span.clone(), Box::new(e)),
+        }
+    }
+    /// Records that this error happened in a file included from the given location
+    pub fn included_from(self, span: Option<&Span>) -> Self {
+        match (self, span) {
+            (e, None) => e,
+            (e, Some(span)) => Error::Included(span.clone(), Box::new(e)),
+        }
+    }
+    /// The error itself without any location information
+    pub fn kind(&self) -> &Self {
+        match self {
+            Error::At(_, e) | Error::Included(_, e) => e.kind(),
+            e => e,
+        }
+    }
+    /// The exit code of the interpreter when stopped by this error.
+    ///
+    /// It is 2 for syntax errors, 3 for I/O errors, including source that isn't
+    /// valid UTF-8, and 1 for any other error.
+    pub fn exit_code(&self) -> i32 {
+        use self::Error::*;
+        match self.kind() {
+            NoBlockStarted | UnclosedBlock | NoListStarted | UnclosedList | UnclosedString => 2,
+            IoError(_) | CharsError(_) => 3,
+            _ => 1,
+        }
+    }
+    /// Where the error happened, if known
+    pub fn span(&self) -> Option<&Span> {
+        match self {
+            Error::At(span, _) => Some(span),
+            Error::Included(_, e) => e.span(),
+            _ => None,
+        }
+    }
+    /// The locations of the `inc` commands leading to the error, innermost first
+    pub fn include_chain(&self) -> Vec<&Span> {
+        let mut chain = match self {
+            Error::At(_, e) | Error::Included(_, e) => e.include_chain(),
+            _ => Vec::new(),
+        };
+        if let Error::Included(span, _) = self {
+            chain.push(span);
+        }
+        chain
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            EmptyStack => write!(f, "empty stack"),
+            OutOfBounds => write!(f, "out of bounds"),
+            InvalidAssignArg => write!(f, "can only assign value to a variable name"),
+            InvalidApplyArg => write!(f, "can only execute blocks"),
+            InvalidSplitArg => write!(f, "split takes a number and a block, list or string"),
+            InvalidGetArg => write!(f, "get takes a number and a block, list or string"),
+            InvalidMoveArg => write!(f, "move takes a number and one other value"),
+            InvalidGrabArg => write!(f, "can only take number as grab argument"),
+            InvalidExitCode => write!(f, "exit code must be from 0 to 255"),
+            InvalidWhileArg => write!(f, "while takes a condition block and a body block"),
+            InvalidTimesArg => write!(f, "times takes a block and a number"),
+            InvalidEachArg => write!(f, "each takes a block, list or string and a body block"),
+            InvalidCondArg => write!(f, "cond takes a list of conditions each followed by a branch"),
+            InvalidTryArg => write!(f, "try takes a body block and a handler block"),
+            NoLoop => write!(f, "cannot break or continue outside of a loop"),
+            InvalidMapArg => write!(f, "expected a map, or a list of keys and values to make one"),
+            InvalidKey => write!(f, "map keys can only be strings, integers or bools"),
+            TypeError { op, lhs_type, rhs_type: Some(rhs_type) } => write!(f, "cannot use `{}` on {} and {}", op, lhs_type, rhs_type),
+            TypeError { op, lhs_type, rhs_type: None } => write!(f, "cannot use `{}` on {}", op, lhs_type),
+            DivisionByZero => write!(f, "division by zero"),
+            NegativeRepeat => write!(f, "cannot repeat something a negative number of times"),
+            RepeatOverflow => write!(f, "too many repetitions"),
+            UndefinedVariable(name) => write!(f, "undefined variable `{}`", name),
+            InvalidCast { from, to } => write!(f, "cannot cast {} to {}", from, to),
+            UnknownPragma(pragma) => write!(f, "unknown pragma {}", pragma),
+            LimitExceeded(limit) => write!(f, "{} limit exceeded", limit),
+            PermissionDenied(capability) => write!(f, "permission denied for {}", capability),
+            Thrown { message, .. } => write!(f, "{}", message),
+            InvalidIncludeArg => write!(f, "include can only take a string"),
+            NoBlockStarted => write!(f, "cannot end a block when none has been started"),
+            UnclosedBlock => write!(f, "block was started but never ended"),
+            NoListStarted => write!(f, "cannot end a list when none has been started"),
+            UnclosedList => write!(f, "list was started but never ended"),
+            UnclosedString => write!(f, "string was started but never ended"),
+            ListUnderflow => write!(f, "list took values from outside of it"),
+            IoError(e) => e.fmt(f),
+            CharsError(e) => e.fmt(f),
+            At(_, e) | Included(_, e) => e.fmt(f),
+        }
+    }
 }
 
 impl From<IoError> for Error {
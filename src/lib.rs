@@ -1,26 +1,46 @@
 #![warn(clippy::all)]
 
 use std::{
+    convert::TryFrom,
     fs::File,
     io::{BufRead, BufReader, Read, Write},
 };
 
+use num_bigint::BigInt;
+
 mod chars;
 mod cmd;
+mod code;
 mod err;
+mod limits;
+mod permissions;
+mod program;
+mod span;
 mod state;
 mod value;
+mod var;
+mod trace;
+mod vm;
 mod tokeniser;
 
-use crate::chars::*;
 use crate::cmd::Command;
 use crate::cmd::Command::*;
-use crate::value::Value;
+use crate::code::Code;
+use crate::value::{Key, Value};
+use crate::var::Var;
 use crate::value::Value::*;
-use crate::tokeniser::Tokeniser;
+use crate::span::Spanned;
+use crate::vm::{Control, Flow};
 
 pub use crate::err::{Error, Result};
+pub use crate::limits::{Limit, Limits};
+pub use crate::permissions::{Capability, Permissions};
+pub use crate::cmd::command_names;
+pub use crate::program::{compile, compile_named, has_open_string, Program};
+pub use crate::span::Span;
 pub use crate::state::State;
+pub use crate::trace::{Step, Tracer, WriteTracer};
+pub use crate::vm::Status;
 
 pub struct InOuter<W: Write, R: Read> {
     o: W,
@@ -37,96 +57,109 @@ impl<W: Write, R: Read> InOuter<W, R> {
     }
 }
 
-pub fn run_with_state<R, R2, W>(src: R, state: &mut State, io: &mut InOuter<W, R2>) -> Result<()>
+pub fn run_with_state<R, R2, W>(src: R, state: &mut State, io: &mut InOuter<W, R2>) -> Result<Status>
 where
     R: Read,
     R2: Read,
     W: Write,
 {
-    for c in Tokeniser::from_char_iter(src.chars_iterator(), |s| Command::from_str_pure(s).is_some()) {
-        match c {
-            Ok((buf, token)) => {
-                if !token.should_ignore() {
-                    run_command(state, Command::from_str(&buf), io)?;
-                }
-            }
-            Err(e) => return Err(Error::CharsError(e)),
-        }
+    run_named_with_state("<input>", src, state, io)
+}
+
+/// Like `run_with_state`, but with the name of the source used for error locations
+pub fn run_named_with_state<R, R2, W>(name: &str, src: R, state: &mut State, io: &mut InOuter<W, R2>) -> Result<Status>
+where
+    R: Read,
+    R2: Read,
+    W: Write,
+{
+    // Code that fails to parse is thrown away as a whole, so none of it runs
+    // with the next input
+    let parser = state.parser.clone();
+    if let Err(e) = program::commands(name, src).try_for_each(|cmd| state.parser.feed(cmd?)) {
+        state.parser = parser;
+        return Err(e);
     }
 
+    state.parser.take_ready().run(state, io)
+}
+
+fn binop<T: Into<Value>, F: FnOnce(Value, Value) -> Result<T>>(s: &mut State, f: F) -> Result<()> {
+    let b = s.pop()?;
+    let a = s.pop()?;
+
+    s.push(f(a, b)?.into());
     Ok(())
 }
 
-fn binop<T: Into<Value>, F: FnOnce(Value, Value) -> T>(s: &mut State, f: F) -> Result<()> {
+/// Like `binop`, but first makes sure the result, which would be `size` long,
+/// stays within the size limit
+fn grow(s: &mut State, f: fn(Value, Value) -> Result<Value>, size: fn(&Value, &Value) -> u64) -> Result<()> {
     let b = s.pop()?;
     let a = s.pop()?;
 
-    s.push(f(a, b).into());
+    s.check_size(size(&a, &b))?;
+    s.push(f(a, b)?);
     Ok(())
 }
 
-use std::mem::replace;
-use std::ops;
+/// Pops a variable name and a value in either order and binds them with `bind`
+fn define(state: &mut State, bind: fn(&mut State, Var, Value)) -> Result<()> {
+    match state.pop_pure()? {
+        Variable(h) => match state.pop()? {
+            Variable(_) => return Err(Error::InvalidAssignArg),
+            v => bind(state, h, v),
+        },
+        v => match state.pop_pure()? {
+            Variable(h) if state.get_var(h).is_none() => bind(state, h, v),
+            _ => return Err(Error::InvalidAssignArg),
+        },
+    }
+    Ok(())
+}
 
-fn run_command<W: Write, R: Read>(state: &mut State, cmd: Command, io: &mut InOuter<W, R>) -> Result<()> {
-    if cfg!(feature = "debug") {
-        println!(
-            "{f}  {indent}{:?}: {:?}",
-            cmd,
-            state.stack(),
-            f = if cmd == BeginBlock { "\n" } else { "" },
-            indent = "    ".repeat((state.block_nesting as usize).saturating_sub(if cmd == EndBlock { 1 } else { 0 })),
-        );
+/// Pops the index given to commands like `get`, which fails with
+/// `OutOfBounds` if it's negative, or `invalid` if it isn't an integer
+fn pop_index(state: &mut State, invalid: Error) -> Result<usize> {
+    match state.pop()? {
+        Integer(n) => usize::try_from(n).map_err(|_| Error::OutOfBounds),
+        Big(_) => Err(Error::OutOfBounds),
+        _ => Err(invalid),
     }
+}
 
-    match cmd {
-        EndBlock => match state.block_nesting {
-            0 => return Err(Error::NoBlockStarted),
-            1 => {
-                state.block_nesting = 0;
+/// Runs a cast, which gives `lax` instead of failing when not in strict mode
+fn cast(state: &mut State, f: fn(Value) -> Result<Value>, lax: Value) -> Result<()> {
+    let v = match f(state.pop()?) {
+        Err(Error::InvalidCast { .. }) if !state.is_strict() => lax,
+        v => v?,
+    };
+    state.push(v);
+    Ok(())
+}
 
-                let t = replace(&mut state.temp, Vec::new());
-                state.push(Block(1, t));
-            }
-            _ => {
-                state.block_nesting -= 1;
-                state.temp.push(EndBlock);
-            }
-        },
-        BeginBlock => {
-            state.block_nesting += 1;
-            if state.block_nesting > 1 {
-                state.temp.push(BeginBlock);
-            }
-        }
-        ref cmd if state.block_nesting > 0 => state.temp.push(cmd.clone()),
-        Value(s) => state.push(s),
+use std::ops;
+
+/// Runs a single command, telling the interpreter loop how to continue
+pub(crate) fn exec_command<W: Write, R: Read>(state: &mut State, cmd: &Command, span: Option<&Span>, io: &mut InOuter<W, R>) -> Result<Flow> {
+    match *cmd {
+        BeginBlock | BeginClosure | EndBlock | Quote(_) | Capture(_) => unreachable!("blocks are resolved when parsing"),
+        BeginList | EndList | Collect(_) => unreachable!("lists are resolved when parsing"),
+        Value(ref s) => state.push(s.clone()),
         Include => match state.pop()? {
             Str(s) => {
-                let file = File::open(s)?;
-                run_with_state(BufReader::new(file), state, io)?;
+                let path = state.permissions().readable(&s)?;
+                let file = File::open(path)?;
+                let program = compile_named(&s, BufReader::new(file)).map_err(|e| e.included_from(span))?;
+                state.check_names()?;
+                return Ok(Flow::Include(program));
             }
             _ => return Err(Error::InvalidIncludeArg),
         },
         Pack => {
-            let mut to_push = Vec::new();
-
-            for val in state.drain_stack() {
-                match val {
-                    Block(n, cmds) => {
-                        to_push.push(BeginBlock);
-                        for _ in 0..n {
-                            for cmd in &cmds {
-                                to_push.push(cmd.clone());
-                            }
-                        }
-                        to_push.push(EndBlock);
-                    }
-                    v => to_push.push(Value(v)),
-                }
-            }
+            let to_push: Vec<_> = state.drain_stack().map(|v| Spanned::bare(Value(v))).collect();
 
-            state.push(Block(1, to_push));
+            state.push(Block(1, Code::from(to_push)));
         }
         Size => {
             let size = state.stack().len() as i64;
@@ -134,19 +167,21 @@ fn run_command<W: Write, R: Read>(state: &mut State, cmd: Command, io: &mut InOu
         }
         Length => {
             let to_push = match *state.peek()? {
-                Block(n, ref b) => Integer((n as usize * b.len()) as i64),
+                Block(n, ref b) => Value::from(BigInt::from(n) * code::elements(b).count()),
+                List(ref l) => Integer(l.len() as i64),
+                Map(ref m) => Integer(m.len() as i64),
                 Str(ref s) => Integer(s.chars().count() as i64),
                 _ => Null,
             };
             state.push(to_push);
         }
         Dup => {
-            let dup = state.peek()?.clone();
+            let dup = state.peek_pure()?.clone();
             state.push(dup);
         }
         Not => {
             let a = state.pop()?;
-            state.push(!a);
+            state.push((!a)?);
         }
         // A B C -> A ? B: C
         If => {
@@ -156,53 +191,49 @@ fn run_command<W: Write, R: Read>(state: &mut State, cmd: Command, io: &mut InOu
 
             state.push(if condition.as_bool() { when_true } else { when_false });
         }
-        Define => match (state.pop_pure()?, state.pop()?) {
-            (Variable(_), Variable(_)) => return Err(Error::InvalidAssignArg),
-            (Variable(h), v) | (v, Variable(h)) => state.add_var(h, v),
-            _ => return Err(Error::InvalidAssignArg),
-        },
-        ApplyFunction => match state.pop()? {
-            Block(n, b) => {
-                'block: for _ in 0..n {
-                    for cmd in &b {
-                        if let Exit = cmd {
-                            break 'block;
-                        } else {
-                            run_command(state, cmd.clone(), io)?;
-                        }
-                    }
-                }
+        Define => define(state, State::add_var)?,
+        Local => define(state, State::add_local)?,
+        ApplyFunction => {
+            let named = matches!(state.peek_pure()?, Variable(_));
+            match state.pop()? {
+                Block(n, b) if named => return Ok(Flow::CallFunction(n, b)),
+                Block(n, b) => return Ok(Flow::Call(n, b)),
+                s @ Str(_) => state.push(s),
+                _ => return Err(Error::InvalidApplyArg),
             }
-            s @ Str(_) => state.push(s),
-            _ => return Err(Error::InvalidApplyArg),
-        },
+        }
         Read => {
+            state.allowed(state.permissions().stdin, Capability::Stdin)?;
             let mut line = String::new();
             io.i.read_line(&mut line)?;
             line = line.trim_end().to_owned();
             state.push(Str(line));
         }
         Swap => {
-            let a = state.pop()?;
-            let b = state.pop()?;
+            let a = state.pop_pure()?;
+            let b = state.pop_pure()?;
             state.push(a);
             state.push(b);
         }
         Split => {
-            let i = match state.pop()? {
-                Integer(n) => n,
-                _ => return Err(Error::InvalidSplitArg),
-            } as usize;
+            let i = pop_index(state, Error::InvalidSplitArg)?;
+            let v = state.pop()?;
+            state.check_size(v.size())?;
 
-            match state.pop()?.flatten() {
-                Block(n, mut b) => {
+            match v.flatten()? {
+                Block(n, b) => {
                     debug_assert_eq!(n, 1, "value has been flattened");
 
-                    let i = b.len().checked_sub(i).ok_or(Error::OutOfBounds)?;
+                    let i = code::split_point_back(&b, i).ok_or(Error::OutOfBounds)?;
 
-                    let right = b.split_off(i);
-                    state.push(Block(1, b));
-                    state.push(Block(1, right));
+                    state.push(Block(1, b.slice(0, i)));
+                    state.push(Block(1, b.slice(i, b.len())));
+                }
+                List(mut l) => {
+                    let i = l.len().checked_sub(i).ok_or(Error::OutOfBounds)?;
+                    let right = l.split_off(i);
+                    state.push(List(l));
+                    state.push(List(right));
                 }
                 Str(mut s) => {
                     let len = s.chars().count();
@@ -219,20 +250,27 @@ fn run_command<W: Write, R: Read>(state: &mut State, cmd: Command, io: &mut InOu
             }
         }
         Get => {
-            let i = match state.pop()? {
-                Integer(n) => n,
-                _ => return Err(Error::InvalidGetArg),
-            } as usize;
+            let i = pop_index(state, Error::InvalidGetArg)?;
+            let v = state.pop()?;
+            state.check_size(v.size())?;
 
-            match state.pop()?.flatten() {
-                Block(n, mut b) => {
+            match v.flatten()? {
+                Block(n, b) => {
                     debug_assert_eq!(n, 1, "value has been flattened");
 
-                    let i = b.len().checked_sub(i + 1).ok_or(Error::OutOfBounds)?;
+                    let (start, end) = code::element_back(&b, i).ok_or(Error::OutOfBounds)?;
 
-                    let cmd = b.remove(i);
-                    state.push(Block(1, b));
-                    state.push(Block(1, vec![cmd]));
+                    let elem = b.slice(start, end);
+                    let mut rest = b.into_vec();
+                    rest.drain(start..end);
+                    state.push(Block(1, rest.into()));
+                    state.push(Block(1, elem));
+                }
+                List(mut l) => {
+                    let i = i.checked_add(1).and_then(|i| l.len().checked_sub(i)).ok_or(Error::OutOfBounds)?;
+                    let elem = l.remove(i);
+                    state.push(List(l));
+                    state.push(elem);
                 }
                 Str(mut s) => {
                     let len = s.chars().count();
@@ -254,22 +292,22 @@ fn run_command<W: Write, R: Read>(state: &mut State, cmd: Command, io: &mut InOu
             }
         }
         DupGet => {
-            let i = match state.pop()? {
-                Integer(n) => n,
-                _ => return Err(Error::InvalidGetArg),
-            } as usize;
+            let i = pop_index(state, Error::InvalidGetArg)?;
 
             let val = match state.peek()? {
                 Block(n, b) => {
-                    let len = *n as usize * b.len();
-                    let i = len.checked_sub(i + 1).ok_or(Error::OutOfBounds)?;
-
-                    let real_index = i % b.len();
+                    let count = code::elements(b).count();
+                    // Every repetition has the same elements, so only the
+                    // index within one of them matters
+                    if count == 0 || (i / count) as u64 >= *n {
+                        return Err(Error::OutOfBounds);
+                    }
 
-                    let cmd = b[real_index].clone();
+                    let (start, end) = code::element_back(b, i % count).unwrap();
 
-                    Block(1, vec![cmd])
+                    Block(1, b.slice(start, end))
                 }
+                List(l) => l.iter().rev().nth(i).ok_or(Error::OutOfBounds)?.clone(),
                 Str(s) => {
                     let c = s.chars().rev().nth(i).ok_or(Error::OutOfBounds)?;
 
@@ -280,69 +318,239 @@ fn run_command<W: Write, R: Read>(state: &mut State, cmd: Command, io: &mut InOu
 
             state.push(val);
         }
-        Move => match state.pop()? {
-            Integer(n) => {
-                let elem = state.peek()?.clone();
+        Move => {
+            let n = pop_index(state, Error::InvalidMoveArg)?;
+            let elem = state.peek_pure()?.clone();
 
-                state.insert(n as usize, elem)?;
-                state.pop()?;
-            }
-            _ => return Err(Error::InvalidMoveArg),
-        },
-        Grab => match state.pop()? {
-            Integer(n) => {
-                let n_elem = state.take_nth(n as usize)?;
+            state.insert(n, elem)?;
+            state.pop_pure()?;
+        }
+        Grab => {
+            let n = pop_index(state, Error::InvalidGrabArg)?;
+            let n_elem = state.take_nth(n)?;
 
-                state.push(n_elem);
-            }
-            _ => return Err(Error::InvalidGrabArg),
-        },
-        DupGrab => match state.pop()? {
-            Integer(n) => {
-                let n_elem = state.nth(n as usize)?.clone();
+            state.push(n_elem);
+        }
+        DupGrab => {
+            let n = pop_index(state, Error::InvalidGrabArg)?;
+            let n_elem = state.nth(n)?.clone();
 
-                state.push(n_elem);
-            }
-            _ => return Err(Error::InvalidGrabArg),
-        },
+            state.push(n_elem);
+        }
         Drop => {
-            state.pop()?;
+            state.pop_pure()?;
         }
         Type => {
-            let t = match state.pop()? {
-                Float(_) => "float",
-                Integer(_) => "int",
-                Bool(_) => "bool",
-                Str(_) => "str",
-                Variable(_) => "var",
-                Block(_, _) => "block",
-                Null => "null",
-            }
-            .into();
+            let t = state.pop()?.type_name().into();
             state.push(t);
         }
-        ToFloat => state.last_mut()?.make_float(),
-        ToInt => state.last_mut()?.make_int(),
-        ToBool => state.last_mut()?.make_bool(),
-        Eq => binop(state, |a, b| a == b)?,
-        Neq => binop(state, |a, b| a != b)?,
-        GreaterThan => binop(state, |a, b| a > b)?,
-        GreaterEquals => binop(state, |a, b| a >= b)?,
-        LessThan => binop(state, |a, b| a < b)?,
-        LessEquals => binop(state, |a, b| a <= b)?,
-        Write => write!(io.o, "{}", state.pop()?)?,
-        Print => writeln!(io.o, "{}", state.pop()?)?,
-        Exit => return Err(Error::Exit),
+        ToFloat => cast(state, Value::into_float, Float(f64::NAN))?,
+        ToInt => cast(state, Value::into_int, Null)?,
+        ToBool => {
+            let b = state.pop()?.as_bool();
+            state.push(Bool(b));
+        }
+        ToList => {
+            state.check_size(state.peek()?.size())?;
+            cast(state, Value::into_list, Null)?
+        }
+        ToBlock => cast(state, Value::into_block, Null)?,
+        ToMap => cast(state, Value::into_map, Null)?,
+        Pragma => match state.pop()? {
+            Str(ref s) if s == "strict" => state.set_strict(true),
+            Str(ref s) if s == "lax" => state.set_strict(false),
+            v => return Err(Error::UnknownPragma(format!("{:?}", v))),
+        },
+        Insert => {
+            let v = state.pop()?;
+            let k = Key::try_from(state.pop()?)?;
+            match state.pop()? {
+                Map(mut m) => {
+                    state.check_size(m.len() as u64 + 1)?;
+                    m.insert(k, v);
+                    state.push(Map(m));
+                }
+                _ => return Err(Error::InvalidMapArg),
+            }
+        }
+        Lookup => {
+            let k = Key::try_from(state.pop()?)?;
+            let v = match state.peek()? {
+                Map(m) => m.get(&k).cloned().unwrap_or(Null),
+                _ => return Err(Error::InvalidMapArg),
+            };
+            state.push(v);
+        }
+        Remove => {
+            let k = Key::try_from(state.pop()?)?;
+            match state.pop()? {
+                Map(mut m) => {
+                    let v = m.remove(&k).unwrap_or(Null);
+                    state.push(Map(m));
+                    state.push(v);
+                }
+                _ => return Err(Error::InvalidMapArg),
+            }
+        }
+        Keys => {
+            let keys = match state.peek()? {
+                Map(m) => m.keys().cloned().map(Value::from).collect(),
+                _ => return Err(Error::InvalidMapArg),
+            };
+            state.push(List(keys));
+        }
+        Has => {
+            let k = Key::try_from(state.pop()?)?;
+            let has = match state.peek()? {
+                Map(m) => m.contains_key(&k),
+                _ => return Err(Error::InvalidMapArg),
+            };
+            state.push(Bool(has));
+        }
+        Eq => binop(state, |a, b| Ok(a == b))?,
+        Neq => binop(state, |a, b| Ok(a != b))?,
+        GreaterThan => binop(state, |a, b| Ok(a > b))?,
+        GreaterEquals => binop(state, |a, b| Ok(a >= b))?,
+        LessThan => binop(state, |a, b| Ok(a < b))?,
+        LessEquals => binop(state, |a, b| Ok(a <= b))?,
+        Write => {
+            state.allowed(state.permissions().stdout, Capability::Stdout)?;
+            write!(io.o, "{}", state.pop()?)?
+        }
+        Print => {
+            state.allowed(state.permissions().stdout, Capability::Stdout)?;
+            writeln!(io.o, "{}", state.pop()?)?
+        }
+        Exit => {
+            let code = match state.peek() {
+                // Processes only get the lowest 8 bits of their exit code
+                Ok(&Integer(n)) => u8::try_from(n).map_err(|_| Error::InvalidExitCode)?.into(),
+                Ok(Big(_)) => return Err(Error::InvalidExitCode),
+                Ok(_) | Err(Error::EmptyStack) => return Ok(Flow::Exit(0)),
+                Err(e) => return Err(e),
+            };
+            state.pop()?;
+            return Ok(Flow::Exit(code));
+        }
+        Return => return Ok(Flow::Return),
+        Try => match (state.pop()?, state.pop()?) {
+            (Block(hn, handler), Block(bn, body)) => return Ok(Flow::Try { body: (bn, body), handler: (hn, handler) }),
+            _ => return Err(Error::InvalidTryArg),
+        },
+        Throw => {
+            let (kind, message) = match state.pop()? {
+                // A caught error can be thrown again as it is
+                Map(mut m) => {
+                    let mut field = |name: &str| m.remove(&Key::Str(name.to_owned())).map(|v| v.to_string());
+                    let kind = field("kind");
+                    (kind, field("message"))
+                }
+                v => (None, Some(v.to_string())),
+            };
+            return Err(Error::Thrown {
+                kind: kind.unwrap_or_else(|| "Error".to_owned()),
+                message: message.unwrap_or_default(),
+            });
+        }
+        While => match (state.pop()?, state.pop()?) {
+            (Block(bn, body), Block(cn, cond)) => return Ok(Flow::Control(Control::While { cond: (cn, cond), body: (bn, body), checking: false })),
+            _ => return Err(Error::InvalidWhileArg),
+        },
+        Times => match (state.pop()?, state.pop()?) {
+            (Integer(n), Block(bn, body)) => {
+                let left = if n < 0 { 0 } else { n as u64 };
+                return Ok(Flow::Control(Control::Times { body: (bn, body), left }));
+            }
+            _ => return Err(Error::InvalidTimesArg),
+        },
+        Each => {
+            let body = match state.pop()? {
+                Block(n, body) => (n, body),
+                _ => return Err(Error::InvalidEachArg),
+            };
+            let items = match state.pop()? {
+                List(l) => l,
+                v @ Block(_, _) | v @ Str(_) => {
+                    state.check_size(v.size())?;
+                    match v.into_list()? {
+                        List(l) => l,
+                        _ => unreachable!("blocks and strings become lists"),
+                    }
+                }
+                _ => return Err(Error::InvalidEachArg),
+            };
+            return Ok(Flow::Control(Control::Each { body, items: items.into_iter() }));
+        }
+        Cond => match state.pop()? {
+            List(arms) if arms.len() % 2 == 0 => return Ok(Flow::Control(Control::Cond { arms: arms.into_iter(), pending: None })),
+            _ => return Err(Error::InvalidCondArg),
+        },
+        Break => return Ok(Flow::Break),
+        Continue => return Ok(Flow::Continue),
         Or => binop(state, ops::BitOr::bitor)?,
         And => binop(state, ops::BitAnd::bitand)?,
         Xor => binop(state, ops::BitXor::bitxor)?,
-        Add => binop(state, ops::Add::add)?,
-        Sub => binop(state, ops::Sub::sub)?,
-        Mul => binop(state, ops::Mul::mul)?,
-        Pow => binop(state, Value::pow)?,
+        Add => grow(state, ops::Add::add, Value::sum_size)?,
+        Sub => grow(state, ops::Sub::sub, Value::sum_size)?,
+        Mul => grow(state, ops::Mul::mul, Value::product_size)?,
+        Pow => grow(state, Value::pow, Value::power_size)?,
         Div => binop(state, ops::Div::div)?,
         Rem => binop(state, ops::Rem::rem)?,
     }
 
-    Ok(())
+    Ok(Flow::Next)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::program::tests::{output, run, run_with};
+    use crate::{compile, run_with_state, Error, InOuter, State};
+
+    #[test]
+    fn failed_input_is_discarded() {
+        let mut state = State::new();
+        let mut io = InOuter::new(Vec::new(), &b""[..]);
+        for src in ["\"leak\" _ }", "{ 1", "2 )", "} len _ 2 _"] {
+            let _ = run_with_state(src.as_bytes(), &mut state, &mut io);
+        }
+        assert_eq!(io.extract().0, b"1\n2\n");
+    }
+
+    #[test]
+    fn states_can_be_sent() {
+        let program = compile(&b"{ 1 + } succ := 1 ^{ succ () } next :="[..]).unwrap();
+        let mut state = thread::spawn(move || {
+            let mut state = State::new();
+            program.run(&mut state, &mut InOuter::new(Vec::new(), &b""[..])).unwrap();
+            state
+        })
+        .join()
+        .unwrap();
+        assert_eq!(run_with("next () _ 2 succ () _", &mut state).1, "2\n3\n");
+    }
+
+    #[test]
+    fn negative_indexes() {
+        let srcs = [
+            "( 1 2 3 ) 0 1 - get", "{ 1 2 3 } 0 1 - get", "\"abc\" 0 1 - get", "( 1 2 3 ) 0 1 - dupget", "{ 1 2 3 } 0 1 - dupget",
+            "( 1 2 3 ) 0 1 - split", "1 2 0 1 - #", "1 2 0 1 - :", "1 2 0 1 - <>", "( 1 2 3 ) 99999999999999999999 get",
+        ];
+        for src in srcs {
+            assert!(matches!(run(src).0.unwrap_err().kind(), Error::OutOfBounds), "{}", src);
+        }
+    }
+
+    #[test]
+    fn maps() {
+        let src = "( \"b\" 2 \"a\" 1 3 \"c\" true \"t\" ) map m :=
+                   m \"zz\" lookup _ ~ m \"zz\" remove _ ~ m \"a\" remove _ keys _ ~
+                   m 3 has _ 4 has _ ~ m type _";
+        assert_eq!(output(src), "NULL\nNULL\n1\n(true, 3, \"b\")\ntrue\nfalse\nmap\n");
+
+        assert!(matches!(run("( ) map 1.5 1 insert").0.unwrap_err().kind(), Error::InvalidKey));
+        assert!(matches!(run("( \"a\" 1 ) map 1.5 lookup").0.unwrap_err().kind(), Error::InvalidKey));
+        assert!(matches!(run("( 1 2 3 ) map").0.unwrap_err().kind(), Error::InvalidMapArg));
+    }
 }
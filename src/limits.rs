@@ -0,0 +1,96 @@
+use std::fmt::{self, Display};
+
+/// Limits on how much a program may do, see `State::set_limits`.
+///
+/// Every limit is off by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// How many commands may run, with every time a block finishes or a loop
+    /// goes around counting as one too
+    pub steps: Option<u64>,
+    /// How many values the stack may hold
+    pub stack: Option<usize>,
+    /// How deeply blocks, loops, list literals and included files may be nested
+    pub depth: Option<usize>,
+    /// How long strings, blocks, lists and maps may get, strings counting
+    /// bytes, and how many bytes big integers may take
+    pub size: Option<u64>,
+    /// How many different variable names may have been used, counting those
+    /// of every program the process has run, as names are never forgotten
+    pub names: Option<usize>,
+}
+
+/// The limit that was exceeded when a program is stopped by `LimitExceeded`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Steps,
+    Stack,
+    Depth,
+    Size,
+    Names,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Steps => "step",
+            Limit::Stack => "stack length",
+            Limit::Depth => "nesting depth",
+            Limit::Size => "size",
+            Limit::Names => "variable name",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limit, Limits};
+    use crate::err::Error;
+    use crate::program::tests::run_with;
+    use crate::state::State;
+    use crate::var::Var;
+
+    #[test]
+    fn resource_limits() {
+        let exceeded = |src: &str, limits| {
+            let mut state = State::new();
+            state.set_limits(limits);
+            match run_with(src, &mut state).0.unwrap_err().kind() {
+                &Error::LimitExceeded(limit) => limit,
+                e => panic!("unexpected error {}", e),
+            }
+        };
+
+        assert_eq!(exceeded("{ d () } d ()", Limits { depth: Some(100), ..Limits::default() }), Limit::Depth);
+        assert_eq!(exceeded("{ { true } { } while } { ~ } try", Limits { steps: Some(1000), ..Limits::default() }), Limit::Steps);
+        // Going around a loop counts even when the body does nothing
+        assert_eq!(exceeded("{ } 100000000000000 times", Limits { steps: Some(1000), ..Limits::default() }), Limit::Steps);
+        assert_eq!(exceeded("{ } 100000000000000 * ()", Limits { steps: Some(1000), ..Limits::default() }), Limit::Steps);
+        assert_eq!(exceeded("{ true } { 1 } while", Limits { stack: Some(50), ..Limits::default() }), Limit::Stack);
+        let size = Limits { size: Some(1000), ..Limits::default() };
+        assert_eq!(exceeded("\"ab\" 1000000000 *", size), Limit::Size);
+        assert_eq!(exceeded("{ 1 } 1000000000 * list", size), Limit::Size);
+        assert_eq!(exceeded("2 { d * } 36 times", Limits { steps: Some(1000), ..size }), Limit::Size);
+        assert_eq!(exceeded("2 4000000000 **", size), Limit::Size);
+        assert_eq!(exceeded("2 8000 **", size), Limit::Size);
+        assert_eq!(exceeded("( ) map 1 1 insert 2 2 insert", Limits { size: Some(1), ..size }), Limit::Size);
+
+        let mut state = State::new();
+        state.set_limits(size);
+        run_with("\"ab\" 500 * len 2 7999 ** 1 4000000000 **", &mut state).0.unwrap();
+        assert_eq!(state.stack().len(), 4);
+        assert_eq!(state.stack()[1], 1000.into());
+    }
+
+    #[test]
+    fn names_limit() {
+        let names: Vec<_> = (0..100).map(|i| format!("unused{}", i)).collect();
+        let mut state = State::new();
+        // Other tests may add names at the same time, but never take any away
+        state.set_limits(Limits { names: Some(Var::count() + 10), ..Limits::default() });
+        match run_with(&names.join(" "), &mut state).0.unwrap_err().kind() {
+            Error::LimitExceeded(Limit::Names) => (),
+            e => panic!("unexpected error {}", e),
+        }
+    }
+}
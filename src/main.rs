@@ -1,12 +1,17 @@
 #![warn(clippy::all)]
 
-use clap::{App, Arg};
-use std::fs::File;
-use std::io::{stdin, stdout, Write, BufReader};
+use clap::{App, Arg, ArgMatches};
+use std::fs::{self, File};
+use std::io::{stderr, stdin, stdout, BufReader, BufWriter};
+use std::path::PathBuf;
+use std::process;
 
-use stalch::Error::*;
 use stalch::*;
 
+mod debugger;
+mod profiler;
+mod shell;
+
 fn main() {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -19,54 +24,190 @@ fn main() {
                 .long("interactive")
                 .help("Starts interactive shell"),
         )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("Makes undefined variables and invalid casts errors"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .conflicts_with("interactive")
+                .help("Pauses before the first command to step through the program"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .value_name("FILE")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .conflicts_with_all(&["interactive", "debug"])
+                .help("Writes every command run and the stack before it to stderr, or to FILE"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("FILE")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .conflicts_with_all(&["interactive", "debug", "trace"])
+                .help("Reports how long commands and named blocks took, also writing folded stacks to FILE"),
+        )
+        .arg(
+            Arg::with_name("sandbox")
+                .long("sandbox")
+                .help("Keeps the program from reading files and input unless allowed below"),
+        )
+        .arg(
+            Arg::with_name("allow-dir")
+                .long("allow-dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("sandbox")
+                .help("Lets a sandboxed program read and include files in DIR"),
+        )
+        .arg(
+            Arg::with_name("allow-stdin")
+                .long("allow-stdin")
+                .requires("sandbox")
+                .help("Lets a sandboxed program read input"),
+        )
+        .arg(
+            Arg::with_name("deny-stdout")
+                .long("deny-stdout")
+                .requires("sandbox")
+                .help("Keeps a sandboxed program from writing output as well"),
+        )
+        .arg(limit_arg("max-steps", "Stops the program after N commands"))
+        .arg(limit_arg("max-stack", "Stops the program when the stack holds more than N values"))
+        .arg(limit_arg("max-depth", "Stops the program when blocks are nested more than N deep"))
+        .arg(limit_arg("max-size", "Stops the program when a string, block, list, map or integer would get bigger than N"))
+        .arg(limit_arg("max-names", "Stops the program when more than N different variable names have been used"))
         .get_matches();
     let mut state = State::new();
+    state.set_strict(matches.is_present("strict"));
+    state.set_limits(Limits {
+        steps: limit(&matches, "max-steps"),
+        stack: limit(&matches, "max-stack"),
+        depth: limit(&matches, "max-depth"),
+        size: limit(&matches, "max-size"),
+        names: limit(&matches, "max-names"),
+    });
+    if matches.is_present("sandbox") {
+        state.set_permissions(Permissions {
+            read_dirs: Some(matches.values_of("allow-dir").into_iter().flatten().map(PathBuf::from).collect()),
+            stdin: matches.is_present("allow-stdin"),
+            stdout: !matches.is_present("deny-stdout"),
+        });
+    }
     let mut stdouter = InOuter::new(stdout(), stdin());
 
     if matches.is_present("interactive") {
-        println!("Stalch Interactive Shell");
-        println!("Type $exit to exit");
-        loop {
-            print!("$> ");
-            stdout().flush().unwrap();
+        shell::interactive(state, &mut stdouter);
+    } else {
+        let src = matches.value_of("SOURCE").unwrap();
 
-            let mut s = String::new();
-            stdin().read_line(&mut s).unwrap();
-            if s.trim_end() == "$exit" {
-                println!();
-                break;
+        let file = match File::open(src) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Error, cannot open {}: {}", src, e);
+                process::exit(Error::from(e).exit_code())
             }
-            match run_with_state(s.as_bytes(), &mut state, &mut stdouter) {
-                Ok(()) => (),
-                Err(e) => handle_error(e),
+        };
+        // Compiled as a whole, so blocks left open at the end are errors
+        let program = match compile_named(src, BufReader::new(file)) {
+            Ok(program) => program,
+            Err(e) => fail(e, &[]),
+        };
+        let res = if matches.is_present("debug") {
+            program.run_traced(&mut state, &mut stdouter, &mut debugger::Debugger::new(src))
+        } else if let Some(path) = matches.value_of("trace") {
+            let file = match File::create(path) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("Error, cannot create {}: {}", path, e);
+                    process::exit(Error::from(e).exit_code())
+                }
+            };
+            program.run_traced(&mut state, &mut stdouter, &mut WriteTracer::new(BufWriter::new(file)))
+        } else if matches.is_present("profile") {
+            let mut profiler = profiler::Profiler::new();
+            let res = program.run_traced(&mut state, &mut stdouter, &mut profiler);
+            profiler.report();
+            if let Some(path) = matches.value_of("profile") {
+                if let Err(e) = profiler.write_folded(path) {
+                    eprintln!("Error, cannot write {}: {}", path, e);
+                }
             }
-            println!(" >{:?}", state.show_stack());
+            res
+        } else if matches.is_present("trace") {
+            program.run_traced(&mut state, &mut stdouter, &mut WriteTracer::new(stderr()))
+        } else {
+            program.run(&mut state, &mut stdouter)
+        };
+        match res {
+            Ok(Status::Done) => (),
+            Ok(Status::Exit(code)) => process::exit(code),
+            Err(e) => fail(e, &[]),
         }
-    } else {
-        let src = matches.value_of("SOURCE").unwrap();
+    }
+}
+
+fn limit_arg<'a>(name: &'a str, help: &'a str) -> Arg<'a, 'a> {
+    Arg::with_name(name)
+        .long(name)
+        .value_name("N")
+        .takes_value(true)
+        .validator(|n| n.parse::<u64>().map(|_| ()).map_err(|_| format!("{} is not a whole number", n)))
+        .help(help)
+}
 
-        let file = File::open(src).unwrap();
-        match run_with_state(BufReader::new(file), &mut state, &mut stdouter) {
-            Ok(()) => (),
-            Err(e) => handle_error(e),
+fn limit<T: std::str::FromStr>(matches: &ArgMatches<'_>, name: &str) -> Option<T> {
+    matches.value_of(name).and_then(|n| n.parse().ok())
+}
+
+/// Name given to the `n`th entry typed into the interactive shell, counting
+/// from 1, so errors can be shown on the entry they happened in
+fn stdin_name(n: usize) -> String {
+    format!("<stdin:{}>", n)
+}
+
+/// Reports the error and exits with the exit code for it
+fn fail(e: Error, entries: &[String]) -> ! {
+    handle_error(&e, entries);
+    process::exit(e.exit_code())
+}
+
+/// Reports the error, showing where it happened in the entries typed into
+/// the interactive shell or in the file
+fn handle_error(e: &Error, entries: &[String]) {
+    eprintln!("Error, {}", e);
+
+    if let Some(span) = e.span() {
+        eprintln!("  --> {}", span);
+        if let Some(line) = source_line(span, entries) {
+            let num = span.line.to_string();
+            let pad = " ".repeat(num.len());
+            let skip = line.chars().take(span.col as usize - 1).map(|c| if c == '\t' { '\t' } else { ' ' });
+            eprintln!(" {} |", pad);
+            eprintln!(" {} | {}", num, line);
+            eprintln!(" {} | {}{}", pad, skip.collect::<String>(), "^".repeat(span.len.max(1) as usize));
         }
     }
+    for span in e.include_chain() {
+        eprintln!("  included from {}", span);
+    }
 }
 
-fn handle_error(e: Error) {
-    match e {
-        IoError(e) => panic!("Unexpected error:\n{:?}", e),
-        CharsError(e) => panic!("Unexpected error:\n{:?}", e),
-        Exit => (),
-        EmptyStack => eprintln!("Error, empty stack"),
-        OutOfBounds => eprintln!("Error, out of bounds"),
-        InvalidAssignArg => eprintln!("Error, can only assign value to a variable name"),
-        InvalidApplyArg => eprintln!("Error, can only execute blocks"),
-        InvalidSplitArg => eprintln!("Error, split takes a number and a block or string"),
-        InvalidGetArg => eprintln!("Error, get takes a number and a block or string"),
-        InvalidMoveArg => eprintln!("Error, move takes a number and one other value"),
-        InvalidGrabArg => eprintln!("Error, can only take number as grab argument"),
-        InvalidIncludeArg => eprintln!("Error, include can only take a string"),
-        NoBlockStarted => eprintln!("Error, cannot end a block when none has been started"),
-    }
+fn source_line(span: &Span, entries: &[String]) -> Option<String> {
+    let entry = span.file.strip_prefix("<stdin:").and_then(|n| n.strip_suffix('>')).and_then(|n| n.parse::<usize>().ok());
+    let src = match entry {
+        Some(n) => entries.get(n.checked_sub(1)?)?.clone(),
+        None => fs::read_to_string(&*span.file).ok()?,
+    };
+    src.lines().nth(span.line as usize - 1).map(str::to_owned)
 }
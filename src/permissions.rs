@@ -0,0 +1,123 @@
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::err::{Error, Result};
+
+/// What programs may do outside of the interpreter, see `State::set_permissions`.
+///
+/// Everything is allowed by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Permissions {
+    /// The directories files may be read from, like by `include`, or `None`
+    /// for anywhere
+    pub read_dirs: Option<Vec<PathBuf>>,
+    /// Whether `read` may read from the input
+    pub stdin: bool,
+    /// Whether `wrte` and `prnt` may write to the output
+    pub stdout: bool,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions { read_dirs: None, stdin: true, stdout: true }
+    }
+}
+
+impl Permissions {
+    /// Permissions that allow nothing but writing output
+    pub fn sandbox() -> Self {
+        Permissions { read_dirs: Some(Vec::new()), stdin: false, stdout: true }
+    }
+    /// The path to read a file at, failing with `PermissionDenied` if it may
+    /// not be read.
+    ///
+    /// The path is resolved, so `..` and links can't lead out of the allowed
+    /// directories. A file that doesn't exist in an allowed directory is left
+    /// for opening it to report.
+    pub(crate) fn readable(&self, path: &str) -> Result<PathBuf> {
+        let dirs = match self.read_dirs {
+            Some(ref dirs) => dirs,
+            None => return Ok(PathBuf::from(path)),
+        };
+        let path = resolve(Path::new(path)).ok_or(Error::PermissionDenied(Capability::ReadFile))?;
+        if dirs.iter().filter_map(|dir| fs::canonicalize(dir).ok()).any(|dir| path.starts_with(dir)) {
+            Ok(path)
+        } else {
+            Err(Error::PermissionDenied(Capability::ReadFile))
+        }
+    }
+}
+
+/// The absolute path with links and `..` resolved, also if the end of it
+/// doesn't exist, as long as that part doesn't go back up with `..`
+fn resolve(path: &Path) -> Option<PathBuf> {
+    if let Ok(resolved) = fs::canonicalize(path) {
+        return Some(resolved);
+    }
+    for existing in path.ancestors().skip(1) {
+        let dir = if existing.as_os_str().is_empty() { Path::new(".") } else { existing };
+        if let Ok(resolved) = fs::canonicalize(dir) {
+            let rest = path.strip_prefix(existing).ok()?;
+            return if rest.components().all(|c| matches!(c, Component::Normal(_))) {
+                Some(resolved.join(rest))
+            } else {
+                None
+            };
+        }
+    }
+    None
+}
+
+/// What a program was denied when it is stopped by `PermissionDenied`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    ReadFile,
+    Stdin,
+    Stdout,
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capability::ReadFile => "reading files",
+            Capability::Stdin => "reading input",
+            Capability::Stdout => "writing output",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capability, Permissions};
+    use crate::err::Error;
+    use crate::program::tests::run_with;
+    use crate::state::State;
+
+    #[test]
+    fn sandbox() {
+        assert!(Permissions::sandbox().stdout);
+        let mut state = State::new();
+        state.set_permissions(Permissions { read_dirs: Some(vec!["examples".into()]), stdout: false, ..Permissions::sandbox() });
+        let mut denied = |src: &str| match run_with(src, &mut state).0 {
+            Ok(_) => None,
+            Err(e) => match *e.kind() {
+                Error::PermissionDenied(capability) => Some(capability),
+                ref e => panic!("unexpected error {}", e),
+            },
+        };
+
+        assert_eq!(denied("\"examples/stdlib.stalch\" inc"), None);
+        assert_eq!(denied("\"examples/../Cargo.toml\" inc"), Some(Capability::ReadFile));
+        assert_eq!(denied("\"Cargo.toml\" inc"), Some(Capability::ReadFile));
+        assert_eq!(denied("\"missing.stalch\" inc"), Some(Capability::ReadFile));
+        assert_eq!(denied("<-"), Some(Capability::Stdin));
+        assert_eq!(denied("1 _"), Some(Capability::Stdout));
+        assert_eq!(denied("{ 1 -> } { \"kind\" lookup } try"), None);
+        assert_eq!(state.stack().last(), Some(&"PermissionDenied".into()));
+        match run_with("\"examples/missing.stalch\" inc", &mut state).0.unwrap_err().kind() {
+            Error::IoError(_) => (),
+            e => panic!("unexpected error {}", e),
+        }
+    }
+}
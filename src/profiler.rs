@@ -0,0 +1,157 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use stalch::*;
+
+/// How often something ran and how long it took altogether
+#[derive(Default, Clone, Copy)]
+struct Count {
+    runs: u64,
+    time: Duration,
+}
+
+impl Count {
+    fn add(&mut self, time: Duration) {
+        self.runs += 1;
+        self.time += time;
+    }
+}
+
+/// Times every command and every block applied through a variable name, for `--profile`
+pub struct Profiler {
+    /// Time spent in each kind of command, not counting the blocks it runs
+    commands: HashMap<&'static str, Count>,
+    /// Time spent in blocks applied through each name, counting everything they run
+    blocks: HashMap<Arc<str>, Count>,
+    /// Time spent in commands under each stack of named blocks, like `main;fib;fib`
+    folded: HashMap<String, Duration>,
+    /// The name each running frame was applied through and when it started, innermost last
+    frames: Vec<(Option<Arc<str>>, Instant)>,
+    /// The name of the block the last command applied, if any
+    applied: Option<Arc<str>>,
+    /// When the running command started
+    start: Instant,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            commands: HashMap::new(),
+            blocks: HashMap::new(),
+            folded: HashMap::new(),
+            frames: Vec::new(),
+            applied: None,
+            start: Instant::now(),
+        }
+    }
+    /// The named blocks being run, outermost first, as a folded stack
+    fn stack(&self) -> String {
+        let mut stack = "main".to_owned();
+        for name in self.frames.iter().filter_map(|(name, _)| name.as_ref()) {
+            stack.push(';');
+            stack.push_str(name);
+        }
+        stack
+    }
+    fn pop_frame(&mut self) {
+        if let Some((Some(name), start)) = self.frames.pop() {
+            // The time of recursive calls is already counted by the outermost one
+            let recursive = self.frames.iter().any(|(outer, _)| outer.as_ref() == Some(&name));
+            let count = self.blocks.entry(name).or_default();
+            count.runs += 1;
+            if !recursive {
+                count.time += start.elapsed();
+            }
+        }
+    }
+    /// Writes how long every command and named block took, slowest first
+    pub fn report(&mut self) {
+        // Blocks stopped by `exit` or an error still count
+        while !self.frames.is_empty() {
+            self.pop_frame();
+        }
+        let mut blocks: Vec<_> = self.blocks.iter().map(|(name, count)| (&**name, *count)).collect();
+        let mut commands: Vec<_> = self.commands.iter().map(|(&name, count)| (name, *count)).collect();
+        blocks.sort_by_key(|&(_, count)| Reverse(count.time));
+        commands.sort_by_key(|&(_, count)| Reverse(count.time));
+
+        for (title, counts) in [("Block", blocks), ("Command", commands)] {
+            if counts.is_empty() {
+                continue;
+            }
+            eprintln!("{:<20} {:>12} {:>14}", title, "Runs", "Time (ms)");
+            for (name, count) in counts {
+                eprintln!("{:<20} {:>12} {:>14.3}", name, count.runs, count.time.as_secs_f64() * 1000.);
+            }
+            eprintln!();
+        }
+    }
+    /// Writes the time spent under each stack of named blocks in microseconds,
+    /// in the folded format flame graph tools read
+    pub fn write_folded(&self, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let mut stacks: Vec<_> = self.folded.iter().collect();
+        stacks.sort();
+        for (stack, time) in stacks {
+            writeln!(out, "{} {}", stack, time.as_micros())?;
+        }
+        out.flush()
+    }
+}
+
+impl Tracer for Profiler {
+    fn before_command(&mut self, step: &Step<'_>, state: &State) {
+        self.applied = step.applied(state);
+        self.start = Instant::now();
+    }
+    fn after_command(&mut self, step: &Step<'_>, _: &State) {
+        let time = self.start.elapsed();
+        self.commands.entry(step.name()).or_default().add(time);
+        *self.folded.entry(self.stack()).or_default() += time;
+    }
+    fn enter_block(&mut self, _: usize, _: &State) {
+        self.frames.push((self.applied.take(), Instant::now()));
+    }
+    fn exit_block(&mut self, _: usize, _: &State) {
+        self.pop_frame();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::Profiler;
+    use stalch::{compile, InOuter, State};
+
+    #[test]
+    fn profiling() {
+        let mut profiler = Profiler::new();
+        let program = compile(&b"{ d 0 > { 1 - countdown () } { } ? () } countdown := 3 countdown () ~"[..]).unwrap();
+        program.run_traced(&mut State::new(), &mut InOuter::new(Vec::new(), &b""[..]), &mut profiler).unwrap();
+        let mut commands: Vec<_> = profiler.commands.iter().map(|(&name, count)| (name, count.runs)).collect();
+        commands.sort();
+        let expected = [("()", 8), ("-", 3), (":=", 1), ("<block>", 9), ("<value>", 13), (">", 4), ("dup", 4), ("if", 4), ("~", 1)];
+        assert_eq!(commands, expected);
+        // Recursive calls count as runs of the block too
+        assert_eq!(profiler.blocks.len(), 1);
+        assert_eq!(profiler.blocks["countdown"].runs, 4);
+
+        let path = env::temp_dir().join(format!("stalch-profile-{}", process::id()));
+        profiler.write_folded(path.to_str().unwrap()).unwrap();
+        let folded = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let stacks: Vec<_> = folded.lines().map(|line| line.rsplit_once(' ').unwrap().0).collect();
+        let expected = [
+            "main", "main;countdown", "main;countdown;countdown", "main;countdown;countdown;countdown",
+            "main;countdown;countdown;countdown;countdown",
+        ];
+        assert_eq!(stacks, expected);
+    }
+}
@@ -0,0 +1,312 @@
+use std::io::{Read, Write};
+use std::mem::take;
+
+use crate::chars::CharsExt;
+use crate::cmd::Command;
+use crate::code::Code;
+use crate::err::*;
+use crate::span::{Span, Spanned};
+use crate::state::State;
+use crate::tokeniser::{Class, Tokeniser};
+use crate::trace::Tracer;
+use crate::vm::{self, Status};
+use crate::InOuter;
+
+/// Stalch code that has already been tokenised and parsed.
+///
+/// It can be run any number of times without touching the source again.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub(crate) code: Code,
+}
+
+impl Program {
+    pub fn run<W: Write, R: Read>(&self, state: &mut State, io: &mut InOuter<W, R>) -> Result<Status> {
+        vm::run(self.code.clone(), state, io, None)
+    }
+    /// Like `run`, but tells the tracer about every command before it runs
+    pub fn run_traced<W: Write, R: Read>(&self, state: &mut State, io: &mut InOuter<W, R>, tracer: &mut dyn Tracer) -> Result<Status> {
+        vm::run(self.code.clone(), state, io, Some(tracer))
+    }
+}
+
+/// Compiles stalch source into a `Program`
+pub fn compile<R: Read>(src: R) -> Result<Program> {
+    compile_named("<input>", src)
+}
+
+/// Like `compile`, but with the name of the source used for error locations
+pub fn compile_named<R: Read>(name: &str, src: R) -> Result<Program> {
+    let mut parser = Parser::default();
+
+    for cmd in commands(name, src) {
+        parser.feed(cmd?)?;
+    }
+
+    parser.finish()
+}
+
+/// Whether the source ends in a string that hasn't been closed, so more of
+/// the source is needed before it can be run
+pub fn has_open_string(src: &str) -> bool {
+    commands("", src.as_bytes()).any(|c| match c {
+        Err(e) => matches!(e.kind(), Error::UnclosedString),
+        Ok(_) => false,
+    })
+}
+
+/// Tokenises the source and turns every token into a command
+pub(crate) fn commands<R: Read>(name: &str, src: R) -> impl Iterator<Item = Result<Spanned<Command>>> {
+    Tokeniser::from_char_iter(name, src.chars_iterator(), |s| Command::from_str_pure(s).is_some())
+        .filter(|c| c.as_ref().map(|(_, token, _)| !token.should_ignore()).unwrap_or(true))
+        .map(|c| match c {
+            Ok((_, Class::UnclosedString, span)) => Err(Error::UnclosedString.at(Some(&span))),
+            Ok((buf, _, span)) => Ok(Spanned::new(Command::from_str(&buf), span)),
+            Err(e) => Err(Error::CharsError(e)),
+        })
+}
+
+/// Resolves nesting by turning the commands between `{` and `}` into block
+/// literals, those between `^{` and `}` into closure literals and those
+/// between `(` and `)` into list literals
+#[derive(Debug, Clone, Default)]
+pub struct Parser {
+    open: Vec<(Bracket, Option<Span>, Vec<Spanned<Command>>)>,
+    ready: Vec<Spanned<Command>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bracket {
+    Block,
+    Closure,
+    List,
+}
+
+impl Bracket {
+    fn unclosed(self) -> Error {
+        match self {
+            Bracket::Block | Bracket::Closure => Error::UnclosedBlock,
+            Bracket::List => Error::UnclosedList,
+        }
+    }
+}
+
+impl Parser {
+    /// How many blocks and lists have been started but not yet ended
+    #[inline]
+    pub fn nesting(&self) -> usize {
+        self.open.len()
+    }
+    /// Gives the parser the next command
+    pub fn feed(&mut self, cmd: Spanned<Command>) -> Result<()> {
+        match cmd.inner {
+            Command::BeginBlock => self.open.push((Bracket::Block, cmd.span, Vec::new())),
+            Command::BeginClosure => self.open.push((Bracket::Closure, cmd.span, Vec::new())),
+            Command::BeginList => self.open.push((Bracket::List, cmd.span, Vec::new())),
+            Command::EndBlock => self.close(Bracket::Block, cmd.span)?,
+            Command::EndList => self.close(Bracket::List, cmd.span)?,
+            _ => self.code().push(cmd),
+        }
+        Ok(())
+    }
+    fn close(&mut self, bracket: Bracket, end: Option<Span>) -> Result<()> {
+        let (open, span, body) = match self.open.pop() {
+            Some(open) => open,
+            None => {
+                let e = match bracket {
+                    Bracket::Block | Bracket::Closure => Error::NoBlockStarted,
+                    Bracket::List => Error::NoListStarted,
+                };
+                return Err(e.at(end.as_ref()));
+            }
+        };
+        let inner = match (open, bracket) {
+            (Bracket::Block, Bracket::Block) => Command::Quote(body.len()),
+            (Bracket::Closure, Bracket::Block) => Command::Capture(body.len()),
+            (Bracket::List, Bracket::List) => Command::Collect(body.len()),
+            _ => return Err(open.unclosed().at(span.as_ref())),
+        };
+        let code = self.code();
+        code.push(Spanned { inner, span });
+        code.extend(body);
+        Ok(())
+    }
+    fn code(&mut self) -> &mut Vec<Spanned<Command>> {
+        match self.open.last_mut() {
+            Some((_, _, body)) => body,
+            None => &mut self.ready,
+        }
+    }
+    /// Takes the code that is not inside any unfinished block or list
+    pub fn take_ready(&mut self) -> Program {
+        Program { code: take(&mut self.ready).into() }
+    }
+    /// Checks that every block and list has been ended
+    pub fn finish(mut self) -> Result<Program> {
+        match self.open.pop() {
+            Some((bracket, span, _)) => Err(bracket.unclosed().at(span.as_ref())),
+            None => Ok(self.take_ready()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::{compile, has_open_string, Error, InOuter, Result, State, Status};
+
+    /// Runs the source with the state, giving how it ended and what it wrote
+    pub(crate) fn run_with(src: &str, state: &mut State) -> (Result<Status>, String) {
+        let mut io = InOuter::new(Vec::new(), &b""[..]);
+        let res = compile(src.as_bytes()).and_then(|program| program.run(state, &mut io));
+        (res, String::from_utf8(io.extract().0).unwrap())
+    }
+
+    /// Runs the source with a new state, giving how it ended and what it wrote
+    pub(crate) fn run(src: &str) -> (Result<Status>, String) {
+        run_with(src, &mut State::new())
+    }
+
+    /// Runs the source with a new state, giving what it wrote, which it
+    /// should do without failing
+    pub(crate) fn output(src: &str) -> String {
+        let (res, out) = run(src);
+        res.unwrap();
+        out
+    }
+
+    #[test]
+    fn program_is_reusable() {
+        let program = compile(&b"{ 1 + } succ := 0 succ () succ () _"[..]).unwrap();
+        let mut io = InOuter::new(Vec::new(), &b""[..]);
+
+        for _ in 0..3 {
+            program.run(&mut State::new(), &mut io).unwrap();
+        }
+        assert_eq!(io.extract().0, b"2\n2\n2\n");
+    }
+
+    #[test]
+    fn unbalanced_blocks() {
+        assert!(compile(&b"{ 1 { 2 }"[..]).is_err());
+        assert!(compile(&b"1 }"[..]).is_err());
+        assert!(compile(&b"( 1 }"[..]).is_err());
+        assert!(compile(&b"{ 1 )"[..]).is_err());
+    }
+
+    #[test]
+    fn list_literals() {
+        assert_eq!(output("(1 2 3 +) dup _ len _ ( ) (\"a\" {1}) + _"), "(1, 5)\n2\n(\"a\", {1})\n");
+    }
+
+    #[test]
+    fn local_variables() {
+        assert_eq!(output("1 nn := { 2 nn local { nn _ 3 nn := } () nn _ } () nn _"), "2\n2\n3\n");
+    }
+
+    #[test]
+    fn closures() {
+        let src = "{ 0 count local ^{ count 1 + count local count } } counter :=
+                   counter () next := counter () other :=
+                   next () _ next () _ other () _
+                   1 nn := ^{ nn } snap := 2 nn := snap () _ { 3 nn local nn } () _";
+        assert_eq!(output(src), "1\n2\n1\n1\n3\n");
+
+        let mut state = State::new();
+        run_with("1 nn := ^{ nn } { nn } ^{ 2 } 3 *", &mut state).0.unwrap();
+        assert_eq!(format!("{:?}", state.show_stack()), "[^{nn}, {nn}, ^{2} * 3]");
+    }
+
+    #[test]
+    fn control_flow() {
+        let src = "k 0 := { true } { k 1 + k := k 3 == { break } { continue } ? () 0 _ } while k _
+                   ( 1 2 3 ) { 2 * } each + + _ { 1 } 3 times + + _
+                   ( false \"no\" { k 3 == } { \"yes\" _ } true \"late\" ) cond
+                   { ( 1 { 2 break } () 3 ) } 5 times _";
+        assert_eq!(output(src), "3\n12\n3\nyes\n(1, 2)\n");
+        // Repeated blocks run all their repetitions each time around
+        let src = "{ 1 _ } 2 * 2 times k 0 := { k 4 < } { k 1 + k := } 2 * while k _ ( 1 2 ) { d } 2 * each size _";
+        assert_eq!(output(src), "1\n1\n1\n1\n4\n6\n");
+        assert!(matches!(run("{ break } ()").0.unwrap_err().kind(), Error::NoLoop));
+    }
+
+    #[test]
+    fn return_and_exit() {
+        let src = "fun { 1 _ true { return } { } ? () 2 _ } := fun () { ( 3 { 4 return } () 5 ) } fun :=
+                   fun () _ 6 _ 7 exit 8 _";
+        let (res, out) = run(src);
+        assert_eq!(res.unwrap(), Status::Exit(7));
+        assert_eq!(out, "1\n(3, 4)\n6\n");
+
+        assert_eq!(run("x").0.unwrap(), Status::Exit(0));
+        assert_eq!(run("255 x").0.unwrap(), Status::Exit(255));
+        for src in ["256 x", "0 1 - x", "99999999999999999999999 x"] {
+            assert!(matches!(run(src).0.unwrap_err().kind(), Error::InvalidExitCode), "{}", src);
+        }
+        let mut state = State::new();
+        state.set_strict(true);
+        assert!(matches!(run_with("nope x", &mut state).0.unwrap_err().kind(), Error::UndefinedVariable(_)));
+    }
+
+    #[test]
+    fn try_and_throw() {
+        let src = "1 { 2 ( 1 ) 5 . } { \"kind\" lookup _ ~ } try size _
+                   { { \"oops\" throw } { throw } try } { \"message\" lookup _ ~ } try
+                   { 3 _ } { 4 _ } try";
+        assert_eq!(output(src), "OutOfBounds\n1\noops\n3\n");
+
+        // Values the body took from below where it started are put back
+        assert_eq!(output("1 2 { ~ ~ ( ) 0 . } { ~ size _ } try"), "2\n");
+        let mut state = State::new();
+        run_with("1 2 3 { ~ { ~ ~ 4 ( ) 0 . } { ~ } try ~ 5 ( ) 0 . } { ~ } try", &mut state).0.unwrap();
+        assert_eq!(format!("{:?}", state.show_stack()), "[1, 2, 3]");
+
+        let err = run("\"oops\" throw").0.unwrap_err();
+        assert_eq!(err.name(), "Error");
+        assert_eq!(err.to_string(), "oops");
+    }
+
+    #[test]
+    fn multi_line_strings() {
+        assert_eq!(output("\"a\nb\" _"), "a\nb\n");
+
+        assert!(has_open_string("1 \"a\nb"));
+        assert!(!has_open_string("1 \"a\nb\" ' \""));
+        assert!(matches!(compile(&b"1 \"a"[..]).unwrap_err().kind(), Error::UnclosedString));
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(run("1 +").0.unwrap_err().exit_code(), 1);
+        assert_eq!(compile(&b"( 1"[..]).unwrap_err().exit_code(), 2);
+        assert_eq!(compile(&b"\"\xff\""[..]).unwrap_err().exit_code(), 3);
+    }
+
+    #[test]
+    fn repeated_blocks() {
+        let src = "{ 1 2 } 70000 * len _ { 1 2 } 4611686018427387904 * d + len _ 9 ; () _ ~ ~ { 1 } 1 1 - * () size _";
+        assert_eq!(output(src), "140000\n18446744073709551616\n1\n0\n");
+        assert_eq!(output("{ } 1000000000000000000 * { 1 } + len _ \"\" 1000000000000000000 * len _"), "1\n0\n");
+
+        // Repeating more than fits in memory fails instead of aborting
+        let srcs = ["{ 1 } 100000000000000 * { 2 } +", "{ 1 } 100000000000000 * 0 get", "{ 1 } 100000000000000 * list", "{ 1 } 100000000000000 * { } each", "\"ab\" 100000000000000 *"];
+        for src in srcs {
+            assert!(matches!(run(src).0.unwrap_err().kind(), Error::RepeatOverflow), "{}", src);
+        }
+    }
+
+    #[test]
+    fn variables_as_source() {
+        let src = "47 v1 := 0 99999999999999999999 - v2 := 0 2.5 - v3 := 1e20 float v4 := ( 1 ( \"k\" 2 ) map ) v5 := { 1 { 2 } ( 3 ) ^{ v1 } } 3 * v6 :=";
+        let mut state = State::new();
+        run_with(src, &mut state).0.unwrap();
+
+        let mut copy = State::new();
+        for (name, v) in state.vars() {
+            run_with(&format!("{} {} :=", v.to_source().unwrap(), name), &mut copy).0.unwrap();
+        }
+        assert_eq!(copy.vars().count(), 6);
+        // Variables never compare equal, so blocks using them are compared as text
+        assert_eq!(format!("{:?}", state.vars().collect::<Vec<_>>()), format!("{:?}", copy.vars().collect::<Vec<_>>()));
+        assert_eq!(crate::Value::from("\"").to_source(), None);
+    }
+}
@@ -0,0 +1,266 @@
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+
+use stalch::*;
+
+use crate::{fail, handle_error, stdin_name};
+
+/// File in the home directory where the history of the interactive shell is kept
+const HISTORY_FILE: &str = ".stalch_history";
+
+/// The documentation of every command, which `:help` shows entries of
+const COMMANDS: &str = include_str!("../COMMANDS.md");
+
+/// The commands of the shell itself, as shown by `:help`
+const SHELL_COMMANDS: &str = "\
+:vars          list the defined variables with their types and values
+:clear         drop everything on the stack
+:reset         start over with no variables and an empty stack
+:load <file>   run a file, keeping what it defines
+:save <file>   write the variables as source that defines them again
+:type <code>   show the type of what the code leaves on top of the stack,
+               without letting it read input or write output
+:help <cmd>    show the documentation of a command
+:undo          undo the last line";
+
+/// Runs the interactive shell until Ctrl-D is pressed
+pub fn interactive<W: Write, R: Read>(mut state: State, io: &mut InOuter<W, R>) {
+    // Values shouldn't go missing when a line fails
+    state.set_rollback(true);
+    let mut editor = match Editor::<Completions, DefaultHistory>::new() {
+        Ok(editor) => editor,
+        Err(e) => fail(readline_error(e), &[]),
+    };
+    editor.set_helper(Some(Completions { vars: Vec::new() }));
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(ref history) = history {
+        // Fails when the shell hasn't been used before, which is fine
+        let _ = editor.load_history(history);
+    }
+
+    println!("Stalch Interactive Shell");
+    println!("Press Ctrl-D to exit, or type :help to see the commands of the shell");
+    // What `:reset` goes back to
+    let initial = state.snapshot();
+    // The state from before the last line was run
+    let mut undo = None;
+    // Lines typed so far of a string that hasn't been closed
+    let mut input = String::new();
+    // Everything run so far, kept to show errors in blocks defined by earlier entries
+    let mut entries = Vec::new();
+    loop {
+        if let Some(completions) = editor.helper_mut() {
+            completions.vars = state.vars().map(|(name, _)| name.to_string()).collect();
+        }
+        let prompt = if input.is_empty() && state.block_nesting() == 0 { "$> " } else { ".. " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C throws away what has been typed of unfinished blocks and strings
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                state.discard_input();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => fail(readline_error(e), &[]),
+        };
+        input.push_str(&line);
+        input.push('\n');
+        if has_open_string(&input) {
+            continue;
+        }
+        let s = mem::take(&mut input);
+
+        let _ = editor.add_history_entry(s.trim_end());
+        if let Some(ref history) = history {
+            let _ = editor.save_history(history);
+        }
+        // A lone `:` is `dupgrab`, so only a name right after it makes a shell command
+        if let Some(cmd) = s.trim().strip_prefix(':').filter(|cmd| cmd.starts_with(char::is_alphabetic)) {
+            let (name, arg) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+            shell_command(name, arg.trim(), &mut state, &mut undo, &initial, &mut entries, io);
+            continue;
+        }
+        undo = Some(state.snapshot());
+        entries.push(s);
+        let s = entries.last().unwrap();
+        match run_named_with_state(&stdin_name(entries.len()), s.as_bytes(), &mut state, io) {
+            Ok(Status::Done) => (),
+            Ok(Status::Exit(code)) => process::exit(code),
+            Err(e) => handle_error(&e, &entries),
+        }
+        if state.block_nesting() == 0 {
+            println!(" >{:?}", state.show_stack());
+        }
+    }
+    println!();
+}
+
+/// Runs a command of the shell itself, like `:vars`
+fn shell_command<W: Write, R: Read>(name: &str, arg: &str, state: &mut State, undo: &mut Option<State>, initial: &State, entries: &mut Vec<String>, io: &mut InOuter<W, R>) {
+    match name {
+        "vars" => {
+            let mut vars: Vec<_> = state.vars().collect();
+            vars.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, v) in vars {
+                println!("{}: {} = {:?}", name, v.type_name(), v);
+            }
+            return;
+        }
+        "save" if !arg.is_empty() => {
+            let mut src = String::new();
+            for (name, v) in state.vars() {
+                match v.to_source() {
+                    Some(v) => src.push_str(&format!("{} {} :=\n", v, name)),
+                    None => eprintln!("Cannot save {}, strings with quotation marks can't be written as source", name),
+                }
+            }
+            if let Err(e) = fs::write(arg, src) {
+                eprintln!("Error, cannot write {}: {}", arg, e);
+            }
+            return;
+        }
+        "type" if !arg.is_empty() => {
+            // Run on a copy that can't read input or write output, so the
+            // code doesn't change anything
+            let mut scratch = state.snapshot();
+            scratch.set_permissions(Permissions { stdin: false, stdout: false, ..state.permissions().clone() });
+            entries.push(arg.to_owned());
+            let res = compile_named(&stdin_name(entries.len()), arg.as_bytes()).and_then(|program| program.run(&mut scratch, io));
+            match res.map(|status| (status, scratch.peek().map(|v| v.type_name()))) {
+                Ok((Status::Exit(code), _)) => println!("exits with code {}", code),
+                Ok((Status::Done, Ok(ty))) => println!("{}", ty),
+                Ok((Status::Done, Err(e))) | Err(e) => handle_error(&e, entries),
+            }
+            return;
+        }
+        "help" if arg.is_empty() => {
+            println!("{}", SHELL_COMMANDS);
+            return;
+        }
+        "help" => {
+            match help(&arg.to_lowercase()) {
+                Some(entry) => println!("### {}", entry.trim_end()),
+                None => eprintln!("There is no command called {}", arg),
+            }
+            return;
+        }
+        "undo" => match undo.take() {
+            Some(previous) => *state = previous,
+            None => eprintln!("Nothing to undo"),
+        },
+        "clear" => {
+            *undo = Some(state.snapshot());
+            state.clear_stack();
+        }
+        "reset" => {
+            *undo = Some(state.snapshot());
+            *state = initial.snapshot();
+        }
+        "load" if !arg.is_empty() => {
+            *undo = Some(state.snapshot());
+            let res = File::open(arg)
+                .map_err(Error::from)
+                .and_then(|file| compile_named(arg, BufReader::new(file)))
+                .and_then(|program| program.run(state, io));
+            match res {
+                Ok(Status::Done) => (),
+                Ok(Status::Exit(code)) => process::exit(code),
+                Err(e) => handle_error(&e, entries),
+            }
+        }
+        "load" | "save" | "type" => {
+            eprintln!("Usage: :{} {}", name, if name == "type" { "<code>" } else { "<file>" });
+            return;
+        }
+        _ => {
+            eprintln!("Unknown shell command :{}, type :help to see them", name);
+            return;
+        }
+    }
+    println!(" >{:?}", state.show_stack());
+}
+
+/// Completes the names of commands, shell commands and defined variables
+struct Completions {
+    /// The names of the variables defined when the line started
+    vars: Vec<String>,
+}
+
+impl Completions {
+    /// The names starting with the given word, sorted
+    fn candidates(&self, word: &str) -> Vec<String> {
+        // Lines that don't start with a command continue the description above
+        let shell_commands = SHELL_COMMANDS.lines().filter_map(|line| line.split_whitespace().next()).filter(|name| name.starts_with(':'));
+        let mut names: Vec<_> = command_names()
+            .chain(shell_commands)
+            .map(str::to_owned)
+            .chain(self.vars.iter().cloned())
+            .filter(|name| name.starts_with(word))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+impl Completer for Completions {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].trim_end_matches(|c: char| !c.is_whitespace()).len();
+        Ok((start, self.candidates(&line[start..pos])))
+    }
+}
+
+impl Hinter for Completions {
+    type Hint = String;
+}
+
+impl Highlighter for Completions {}
+
+impl Validator for Completions {}
+
+impl Helper for Completions {}
+
+/// The entry of `COMMANDS.md` for the command with the given name or alias
+fn help(cmd: &str) -> Option<&'static str> {
+    COMMANDS.split("\n### ").skip(1).find(|entry| {
+        let heading = entry.lines().next().unwrap_or_default();
+        heading.split([',', ';']).any(|alias| alias.trim().trim_matches('`') == cmd)
+    })
+}
+
+fn readline_error(e: ReadlineError) -> Error {
+    match e {
+        ReadlineError::Io(e) => e.into(),
+        e => io::Error::other(e).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Completions;
+
+    #[test]
+    fn completion_candidates() {
+        let completions = Completions { vars: vec!["counter".to_owned(), "width".to_owned()] };
+        assert_eq!(completions.candidates(":"), [":", ":=", ":clear", ":help", ":load", ":reset", ":save", ":type", ":undo", ":vars"]);
+        assert_eq!(completions.candidates("wi"), ["width"]);
+        assert!(completions.candidates("w").contains(&"wrte".to_owned()));
+        assert!(completions.candidates("cou").contains(&"counter".to_owned()));
+        // Only names of commands are offered, not words of their descriptions
+        assert!(completions.candidates("with").is_empty());
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt::{self, Debug, Display};
+use std::sync::Arc;
+
+/// A range of characters on a line of some source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub file: Arc<str>,
+    pub line: u32,
+    pub col: u32,
+    pub len: u32,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+/// Something together with where in the source it came from.
+///
+/// Things created at runtime (e.g. by `pack`) have no span.
+/// Comparisons ignore the span.
+#[derive(Clone)]
+pub struct Spanned<T> {
+    pub inner: T,
+    pub span: Option<Span>,
+}
+
+impl<T> Spanned<T> {
+    #[inline]
+    pub fn new(inner: T, span: Span) -> Self {
+        Spanned { inner, span: Some(span) }
+    }
+    #[inline]
+    pub fn bare(inner: T) -> Self {
+        Spanned { inner, span: None }
+    }
+}
+
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner == other.inner
+    }
+}
+
+impl<T: Debug> Debug for Spanned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
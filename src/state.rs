@@ -1,25 +1,162 @@
-use std::collections::HashMap;
 use std::fmt::{self, Debug};
+use std::sync::Arc;
 
-use crate::cmd::Command;
+use crate::code::EnvCopies;
 use crate::err::*;
+use crate::limits::{Limit, Limits};
+use crate::permissions::{Capability, Permissions};
+use crate::program::Parser;
 use crate::value::Value;
+use crate::var::Var;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct State {
     stack: Vec<Value>,
-    pub block_nesting: u8,
-    vars: HashMap<String, Value>,
-    pub temp: Vec<Command>,
+    /// Values of variables indexed by their slot
+    vars: Vec<Option<Value>>,
+    /// Local variables indexed by their slot, innermost last, along with the
+    /// depth of the scope they belong to
+    locals: Vec<Vec<(usize, Value)>>,
+    /// The variables bound in each open scope
+    scopes: Vec<Vec<Var>>,
+    /// Whether using undefined variables and invalid casts are errors
+    strict: bool,
+    limits: Limits,
+    /// Whether any of the limits checked before every command is set
+    bounded: bool,
+    /// How many commands have run since the limits were set
+    steps: u64,
+    permissions: Permissions,
+    /// Whether failed commands put the stack back even outside of `try`
+    rollback: bool,
+    /// The `try`s that are running, innermost last, in which failed commands
+    /// put the stack back
+    tries: Vec<Floor>,
+    /// The height of the stack when the running command started
+    start: usize,
+    /// The lowest the stack has been since the running command started
+    low: usize,
+    /// The values the running command has taken from below `low`, topmost first
+    journal: Vec<Value>,
+    pub(crate) parser: Parser,
+}
+
+/// How far down the stack the body of a running `try` has gotten, so it can
+/// be put back if the body fails
+#[derive(Debug, Clone)]
+struct Floor {
+    /// The lowest the stack has been since the `try` started
+    low: usize,
+    /// The values the body has taken from below `low`, topmost first
+    saved: Vec<Value>,
 }
 
 impl State {
     pub fn new() -> Self {
         State::default()
     }
+    /// A copy of the state that shares no closure variables with it, unlike
+    /// `clone`, so applying a closure in one of them doesn't change the other
+    pub fn snapshot(&self) -> State {
+        let mut copy = self.clone();
+        let mut envs = EnvCopies::default();
+        let values = copy.stack.iter_mut()
+            .chain(copy.vars.iter_mut().flatten())
+            .chain(copy.locals.iter_mut().flatten().map(|(_, v)| v))
+            .chain(copy.journal.iter_mut())
+            .chain(copy.tries.iter_mut().flat_map(|floor| floor.saved.iter_mut()));
+        for v in values {
+            envs.value(v);
+        }
+        copy
+    }
+    /// How many blocks are still open from code given to `run_with_state`
+    #[inline]
+    pub fn block_nesting(&self) -> usize {
+        self.parser.nesting()
+    }
+    /// Forgets the blocks and lists left open by code given to `run_with_state`
+    pub fn discard_input(&mut self) {
+        self.parser = Parser::default();
+    }
+    #[inline]
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+    #[inline]
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+    /// Sets the limits of what programs run with this state may do, starting
+    /// the count of commands run over
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.bounded = limits.steps.is_some() || limits.stack.is_some() || limits.depth.is_some();
+        self.steps = 0;
+    }
+    #[inline]
+    pub fn permissions(&self) -> &Permissions {
+        &self.permissions
+    }
+    /// Sets what programs run with this state may do outside of the interpreter
+    pub fn set_permissions(&mut self, permissions: Permissions) {
+        self.permissions = permissions;
+    }
+    /// Fails with `PermissionDenied` for the capability unless it is allowed
+    pub(crate) fn allowed(&self, allowed: bool, capability: Capability) -> Result<()> {
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::PermissionDenied(capability))
+        }
+    }
+    /// Counts a command about to run at the given depth, failing if that
+    /// goes over any of the limits
+    #[inline(always)]
+    pub(crate) fn tick(&mut self, depth: usize) -> Result<()> {
+        if self.bounded {
+            self.check_limits(depth)
+        } else {
+            Ok(())
+        }
+    }
+    fn check_limits(&mut self, depth: usize) -> Result<()> {
+        self.steps += 1;
+        let limits = &self.limits;
+        if limits.steps.is_some_and(|max| self.steps > max) {
+            Err(Error::LimitExceeded(Limit::Steps))
+        } else if limits.stack.is_some_and(|max| self.stack.len() > max) {
+            Err(Error::LimitExceeded(Limit::Stack))
+        } else if limits.depth.is_some_and(|max| depth > max) {
+            Err(Error::LimitExceeded(Limit::Depth))
+        } else {
+            Ok(())
+        }
+    }
+    /// Fails if more variable names have been used than the limit allows
+    pub(crate) fn check_names(&self) -> Result<()> {
+        match self.limits.names {
+            Some(max) if Var::count() > max => Err(Error::LimitExceeded(Limit::Names)),
+            _ => Ok(()),
+        }
+    }
+    /// Fails if something of the given size would go over the size limit
+    pub(crate) fn check_size(&self, size: u64) -> Result<()> {
+        match self.limits.size {
+            Some(max) if size > max => Err(Error::LimitExceeded(Limit::Size)),
+            _ => Ok(()),
+        }
+    }
     pub fn drain_stack(&mut self) -> impl Iterator<Item = Value> + '_ {
+        self.touch(0);
         self.stack.drain(..)
     }
+    pub fn clear_stack(&mut self) {
+        self.stack.clear();
+    }
     #[inline(always)]
     pub fn stack(&self) -> &[Value] {
         &self.stack
@@ -32,20 +169,43 @@ impl State {
     }
     #[inline(always)]
     pub fn pop_pure(&mut self) -> Result<Value> {
-        self.stack.pop().ok_or(Error::EmptyStack)
+        let v = self.stack.pop().ok_or(Error::EmptyStack)?;
+        if self.stack.len() < self.low {
+            self.low = self.stack.len();
+            self.journal.push(v.clone());
+        }
+        Ok(v)
     }
     pub fn pop(&mut self) -> Result<Value> {
-        self.pop_pure().map(|v| {
-            if let Value::Variable(v) = v {
-                if let Some(v) = self.get_var(&v) {
-                    v.clone()
-                } else {
-                    Value::Variable(v)
-                }
-            } else {
-                v
-            }
-        })
+        let v = self.pop_pure()?;
+        self.resolve(v)
+    }
+    /// Replaces a variable with its value if it has one
+    fn resolve(&self, v: Value) -> Result<Value> {
+        match v {
+            Value::Variable(var) => match self.get_var(var) {
+                Some(v) => Ok(v.clone()),
+                None => self.undefined(var).map(|()| v),
+            },
+            v => Ok(v),
+        }
+    }
+    /// Fails in strict mode, where undefined variables can't be used
+    fn undefined(&self, var: Var) -> Result<()> {
+        if self.strict {
+            Err(Error::UndefinedVariable(var.name().to_string()))
+        } else {
+            Ok(())
+        }
+    }
+    /// Takes every value from the given stack height and up
+    pub fn take_from(&mut self, height: usize) -> Result<Vec<Value>> {
+        if height > self.stack.len() {
+            return Err(Error::ListUnderflow);
+        }
+        self.touch(height);
+        let vals = self.stack.split_off(height);
+        vals.into_iter().map(|v| self.resolve(v)).collect()
     }
     #[inline(always)]
     pub fn peek_pure(&self) -> Result<&Value> {
@@ -53,45 +213,203 @@ impl State {
     }
     #[inline(always)]
     pub fn peek(&self) -> Result<&Value> {
-        self.peek_pure().map(|v| {
-            if let Value::Variable(v) = v {
-                if let Some(v) = self.get_var(&v) {
-                    v
-                } else {
-                    // TODO HACK FIX this is bad code
-                    self.peek_pure().unwrap()
-                }
-            } else {
-                v
-            }
-        })
+        let v = self.peek_pure()?;
+        match *v {
+            Value::Variable(var) => match self.get_var(var) {
+                Some(v) => Ok(v),
+                None => self.undefined(var).map(|()| v),
+            },
+            _ => Ok(v),
+        }
     }
     #[inline]
     fn index(&self, n: usize) -> Result<usize> {
-        self.stack.len().checked_sub(n + 1).ok_or(Error::OutOfBounds)
+        n.checked_add(1).and_then(|n| self.stack.len().checked_sub(n)).ok_or(Error::OutOfBounds)
     }
     #[inline]
     pub fn insert(&mut self, n: usize, val: Value) -> Result<()> {
-        self.index(n).map(|i| self.stack.insert(i, val))
+        let i = self.index(n)?;
+        self.touch(i);
+        self.stack.insert(i, val);
+        Ok(())
     }
     pub fn nth(&self, n: usize) -> Result<&Value> {
         Ok(&self.stack[self.index(n)?])
     }
     #[inline(always)]
     pub fn last_mut(&mut self) -> Result<&mut Value> {
-        self.stack.last_mut().ok_or(Error::EmptyStack)
+        self.touch(self.stack.len().checked_sub(1).ok_or(Error::EmptyStack)?);
+        Ok(self.stack.last_mut().unwrap())
     }
     pub fn take_nth(&mut self, n: usize) -> Result<Value> {
         let index = self.index(n)?;
+        self.touch(index);
         Ok(self.stack.remove(index))
     }
+    /// Sets whether a failed command leaves the stack as it was before it
+    /// ran even when no `try` catches the error, like the interactive shell
+    /// wants.
+    ///
+    /// This keeps copies of the values commands take from the stack, so it
+    /// is off by default.
+    pub fn set_rollback(&mut self, rollback: bool) {
+        self.rollback = rollback;
+    }
     #[inline(always)]
-    pub fn get_var(&self, var: &str) -> Option<&Value> {
-        self.vars.get(var)
+    fn undoable(&self) -> bool {
+        self.rollback || !self.tries.is_empty()
+    }
+    /// Starts a `try`, whose body can be undone with `fail_try` until `exit_try`
+    pub(crate) fn enter_try(&mut self) {
+        self.settle();
+        self.journal.clear();
+        self.start = self.stack.len();
+        self.low = self.start;
+        self.tries.push(Floor { low: self.start, saved: Vec::new() });
+    }
+    /// Ends the innermost `try` after its body has run
+    pub(crate) fn exit_try(&mut self) {
+        self.tries.pop();
     }
+    /// Ends the innermost `try` after its body has failed, putting the stack
+    /// back to how it was when the `try` started
+    pub(crate) fn fail_try(&mut self) {
+        self.settle();
+        self.journal.clear();
+        if let Some(floor) = self.tries.pop() {
+            self.stack.truncate(floor.low);
+            self.stack.extend(floor.saved.into_iter().rev());
+        }
+        self.start = self.stack.len();
+        self.low = if self.undoable() { self.start } else { 0 };
+    }
+    /// How many `try`s are running, which `run` puts back when it returns an error
+    pub(crate) fn tries(&self) -> usize {
+        self.tries.len()
+    }
+    pub(crate) fn set_tries(&mut self, tries: usize) {
+        self.tries.truncate(tries);
+    }
+    /// Starts a command, which can be undone with `rollback` until the next
+    /// one starts if anything could see the stack after it fails
     #[inline(always)]
-    pub fn add_var(&mut self, var: String, val: Value) {
-        self.vars.insert(var, val);
+    pub(crate) fn begin(&mut self) {
+        if !self.tries.is_empty() {
+            self.settle();
+        }
+        self.start = self.stack.len();
+        // Nothing is ever taken from below 0, so nothing gets copied
+        self.low = if self.undoable() { self.start } else { 0 };
+        self.journal.clear();
+    }
+    /// Gives the running `try`s the values taken from below them since the
+    /// last command started
+    fn settle(&mut self) {
+        for floor in &mut self.tries {
+            if self.low < floor.low {
+                // The journal starts at the top of the stack from when the command started
+                floor.saved.extend(self.journal[self.start - floor.low..].iter().cloned());
+                floor.low = self.low;
+            }
+        }
+    }
+    /// Puts the stack back to how it was when the running command started
+    pub(crate) fn rollback(&mut self) {
+        if self.undoable() {
+            self.stack.truncate(self.low);
+            self.stack.extend(self.journal.drain(..).rev());
+            self.low = self.start;
+        }
+    }
+    /// Records the values from `index` up to `low` before any of them are changed
+    #[inline(always)]
+    fn touch(&mut self, index: usize) {
+        if index < self.low {
+            self.journal.extend(self.stack[index..self.low].iter().rev().cloned());
+            self.low = index;
+        }
+    }
+    /// Looks up a variable, starting with the innermost local one
+    #[inline(always)]
+    pub fn get_var(&self, var: Var) -> Option<&Value> {
+        if let Some((_, v)) = self.locals.get(var.slot()).and_then(|l| l.last()) {
+            return Some(v);
+        }
+        self.vars.get(var.slot()).and_then(Option::as_ref)
+    }
+    /// The names and values of the global variables that have been defined
+    pub fn vars(&self) -> impl Iterator<Item = (Arc<str>, &Value)> + '_ {
+        self.vars.iter().enumerate().filter_map(|(slot, v)| Some((Var::from_slot(slot).name(), v.as_ref()?)))
+    }
+    /// Looks up a variable by its name
+    pub fn lookup(&self, name: &str) -> Option<&Value> {
+        self.get_var(Var::new(name))
+    }
+    pub fn add_var(&mut self, var: Var, val: Value) {
+        let slot = var.slot();
+        if slot >= self.vars.len() {
+            self.vars.resize(slot + 1, None);
+        }
+        self.vars[slot] = Some(val);
+    }
+    /// Binds a variable in the innermost scope, or globally outside of any block
+    pub fn add_local(&mut self, var: Var, val: Value) {
+        let depth = self.scopes.len();
+        let scope = match self.scopes.last_mut() {
+            Some(scope) => scope,
+            None => return self.add_var(var, val),
+        };
+        let slot = var.slot();
+        if slot >= self.locals.len() {
+            self.locals.resize_with(slot + 1, Vec::new);
+        }
+        match self.locals[slot].last_mut() {
+            Some((d, v)) if *d == depth => *v = val,
+            _ => {
+                self.locals[slot].push((depth, val));
+                scope.push(var);
+            }
+        }
+    }
+    /// A variable bound in the innermost scope
+    pub(crate) fn current_local(&self, var: Var) -> Option<&Value> {
+        match self.locals.get(var.slot())?.last() {
+            Some((depth, v)) if *depth == self.scopes.len() => Some(v),
+            _ => None,
+        }
+    }
+    /// How many scopes are open
+    #[inline]
+    pub fn scope_depth(&self) -> usize {
+        self.scopes.len()
+    }
+    pub(crate) fn push_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+    pub(crate) fn pop_scope(&mut self) {
+        if let Some(scope) = self.scopes.pop() {
+            if scope.is_empty() {
+                return;
+            }
+            // Variables are resolved lazily, so names of the locals left on
+            // the stack have to be resolved before the locals disappear
+            for v in &mut self.stack {
+                if let Value::Variable(var) = *v {
+                    if scope.contains(&var) {
+                        *v = self.locals[var.slot()].last().unwrap().1.clone();
+                    }
+                }
+            }
+            for var in scope {
+                self.locals[var.slot()].pop();
+            }
+        }
+    }
+    /// Closes scopes until only `depth` of them are open
+    pub(crate) fn truncate_scopes(&mut self, depth: usize) {
+        while self.scopes.len() > depth {
+            self.pop_scope();
+        }
     }
 }
 
@@ -103,7 +421,7 @@ impl Debug for ShowState<'_> {
         for v in &self.0.stack {
             match v {
                 Value::Variable(s) => {
-                    if let Some(v) = self.0.get_var(s) {
+                    if let Some(v) = self.0.get_var(*s) {
                         dbg.entry(v);
                     } else {
                         dbg.entry(&format_args!("{}", s));
@@ -117,3 +435,67 @@ impl Debug for ShowState<'_> {
         dbg.finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::err::Error;
+    use crate::program::tests::run_with;
+    use crate::state::State;
+
+    #[test]
+    fn strict_mode() {
+        let mut state = State::new();
+        run_with("nmae \"x\" int", &mut state).0.unwrap();
+        assert_eq!(state.stack().len(), 2);
+
+        let mut state = State::new();
+        state.set_strict(true);
+        match run_with("name 1 := nmae _", &mut state).0.unwrap_err().kind() {
+            Error::UndefinedVariable(name) => assert_eq!(name, "nmae"),
+            e => panic!("unexpected error {}", e),
+        }
+        let err = run_with("\"x\" int", &mut state).0.unwrap_err();
+        assert!(matches!(err.kind(), Error::InvalidCast { from: "str", to: "int" }));
+    }
+
+    #[test]
+    fn failed_commands_keep_stack() {
+        let mut state = State::new();
+        state.set_rollback(true);
+        for src in &["\"x\" 5 get", "1 \"a\" -", "9 grab", "( 1 ) \"k\" 1 insert"] {
+            run_with(src, &mut state).0.unwrap_err();
+        }
+        assert_eq!(format!("{:?}", state.show_stack()), r#"["x", 5, 1, "a", 9, (1), "k", 1]"#);
+
+        let mut state = State::new();
+        run_with("1 ( 2 ) { + } { ~ } try", &mut state).0.unwrap();
+        assert_eq!(format!("{:?}", state.show_stack()), "[1, (2)]");
+    }
+
+    #[test]
+    fn commands_outside_try_keep_no_copies() {
+        // Without anything to see the stack after a command fails, what it
+        // took is gone, as copying it would make building a list take
+        // quadratic time
+        let mut state = State::new();
+        run_with("( ) { ( 1 ) + } 100 times len $ ~", &mut state).0.unwrap();
+        run_with("2 \"a\" -", &mut state).0.unwrap_err();
+        assert_eq!(format!("{:?}", state.show_stack()), "[100]");
+
+        run_with("2 \"a\" { - } { ~ } try", &mut state).0.unwrap();
+        assert_eq!(format!("{:?}", state.show_stack()), "[100, 2, \"a\"]");
+    }
+
+    #[test]
+    fn snapshots_keep_closures_apart() {
+        let mut state = State::new();
+        run_with("{ 0 count local ^{ count 1 + count local count } } () next :=", &mut state).0.unwrap();
+        let snapshot = state.snapshot();
+        run_with("next () next ()", &mut state).0.unwrap();
+        assert_eq!(format!("{:?}", state.show_stack()), "[1, 2]");
+
+        let mut state = snapshot;
+        run_with("next () next ()", &mut state).0.unwrap();
+        assert_eq!(format!("{:?}", state.show_stack()), "[1, 2]");
+    }
+}
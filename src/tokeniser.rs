@@ -1,4 +1,7 @@
 use std::iter::Peekable;
+use std::sync::Arc;
+
+use crate::span::Span;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Class {
@@ -6,6 +9,8 @@ pub enum Class {
     Operator,
     LineComment,
     String,
+    /// A string that the source ended in the middle of
+    UnclosedString,
     Number,
     Whitespace
 }
@@ -16,6 +21,7 @@ impl Class {
             Class::Whitespace => true,
             Class::LineComment => true,
             Class::String => false,
+            Class::UnclosedString => false,
             Class::Identifier => false,
             Class::Number => false,
             Class::Operator => false,
@@ -26,7 +32,7 @@ impl Class {
             Class::Identifier => c.is_alphanumeric() || c == '_',
             Class::Number => c.is_numeric() || c == '.' || c == 'e' || c == 'E',
             Class::Whitespace => c.is_whitespace(),
-            Class::String => c != '"' && c != '\n',
+            Class::String | Class::UnclosedString => c != '"',
             Class::LineComment => c != '\n',
             Class::Operator => !(c.is_alphanumeric() || c.is_whitespace() || c == '"'),
         }
@@ -49,21 +55,55 @@ pub struct Tokeniser<I: Iterator<Item=Result<char, E>>, E, F: FnMut(&str) -> boo
     cur_token: Class,
     buf: String,
     is_op: F,
+    file: Arc<str>,
+    /// Line and column of the next character to be read
+    line: u32,
+    col: u32,
+    /// Line and column of the first character in `buf`
+    start: (u32, u32),
+    /// How many characters of source `buf` was read from
+    width: u32,
 }
 
 impl<I: Iterator<Item=Result<char, E>>, E, F: FnMut(&str) -> bool> Tokeniser<I, E, F> {
     #[inline]
-    pub fn new(iter: Peekable<I>, is_op: F) -> Self {
+    pub fn new(file: &str, iter: Peekable<I>, is_op: F) -> Self {
         Self {
             iter,
             is_op,
             cur_token: Class::Whitespace,
             buf: String::new(),
+            file: file.into(),
+            line: 1,
+            col: 1,
+            start: (1, 1),
+            width: 0,
         }
     }
     #[inline]
-    pub fn from_char_iter(chars: I, is_op: F) -> Self {
-        Self::new(chars.peekable(), is_op)
+    pub fn from_char_iter(file: &str, chars: I, is_op: F) -> Self {
+        Self::new(file, chars.peekable(), is_op)
+    }
+    fn bump(&mut self) -> Option<Result<char, E>> {
+        let c = self.iter.next()?;
+        if let Ok(c) = c {
+            if c == '\n' {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
+            self.width += 1;
+        }
+        Some(c)
+    }
+    fn span(&self, len: u32) -> Span {
+        Span {
+            file: self.file.clone(),
+            line: self.start.0,
+            col: self.start.1,
+            len,
+        }
     }
 }
 
@@ -90,72 +130,119 @@ fn escape_char(c: char) -> char {
 }
 
 impl<I: Iterator<Item=Result<char, E>>, E, F: FnMut(&str) -> bool> Iterator for Tokeniser<I, E, F> {
-    type Item = Result<(String, Class), E>;
+    type Item = Result<(String, Class, Span), E>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let &mut Self{ref mut iter, ref mut cur_token, ref mut buf, ref mut is_op} = self;
-
-        let token = *cur_token;
+        let mut token = self.cur_token;
 
-        if buf.is_empty() {
-            iter.peek()?;
+        if self.buf.is_empty() {
+            self.iter.peek()?;
+            self.start = (self.line, self.col);
+            self.width = 0;
 
-            while let Some(peek_c) = iter.peek() {
+            while let Some(peek_c) = self.iter.peek() {
                 let peek_c = match peek_c {
                     Ok(c) => *c,
-                    Err(_) => continue,
+                    Err(_) => return Some(Err(self.iter.next()?.err()?)),
                 };
-                if let Class::String = cur_token {
+                if let Class::String = self.cur_token {
                     match peek_c {
                         '\\' => {
-                            let _a = try_iter!(iter.next().unwrap());
-                            let b = try_iter!(iter.next().unwrap());
-                            buf.push(escape_char(b));
+                            let _a = try_iter!(self.bump().unwrap());
+                            let b = try_iter!(self.bump().unwrap());
+                            self.buf.push(escape_char(b));
                             continue
                         }
                         '"' => {
-                            let was_empty = buf.is_empty();
-                            buf.push(try_iter!(iter.next().unwrap()));
+                            let was_empty = self.buf.is_empty();
+                            let c = try_iter!(self.bump().unwrap());
+                            self.buf.push(c);
                             if was_empty {
                                 continue;
                             } else {
-                                *cur_token = Class::Whitespace;
+                                self.cur_token = Class::Whitespace;
                                 break;
                             }
                         },
                         _ => ()
                     }
                 }
-                if !cur_token.is_continue(peek_c) {
-                    *cur_token = Class::classify_start(peek_c);
+                if !self.cur_token.is_continue(peek_c) {
+                    self.cur_token = Class::classify_start(peek_c);
                     break
                 }
 
-                buf.push(try_iter!(iter.next().unwrap()));
+                let c = try_iter!(self.bump().unwrap());
+                self.buf.push(c);
+            }
+            // Only the end of the source stops a string without closing it
+            if token == Class::String && self.cur_token == Class::String {
+                token = Class::UnclosedString;
             }
         }
 
         if let Class::Operator = token {
-            for i in buf.char_indices().map(|(i, c)| i + c.len_utf8()).rev() {
-                let a = &buf[..i];
-
-                if is_op(a) {
-                    let ret = buf.drain(..i).collect();
-                    if !buf.is_empty() {
-                        *cur_token = Class::Operator;
+            for i in self.buf.char_indices().map(|(i, c)| i + c.len_utf8()).rev() {
+                if (self.is_op)(&self.buf[..i]) {
+                    let ret: String = self.buf.drain(..i).collect();
+                    let len = ret.chars().count() as u32;
+                    let span = self.span(len);
+                    if !self.buf.is_empty() {
+                        self.cur_token = Class::Operator;
+                        self.start.1 += len;
+                        self.width -= len;
                     }
-                    return Some(Ok((ret, token)));
+                    return Some(Ok((ret, token, span)));
                 }
             }
         }
 
-        let ret = buf.clone();
-        buf.clear();
+        let ret = self.buf.clone();
+        self.buf.clear();
 
         if ret.is_empty() {
             self.next()
         } else {
-            Some(Ok((ret, token)))
+            Some(Ok((ret, token, self.span(self.width))))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Class, Tokeniser};
+
+    #[test]
+    fn token_spans() {
+        let src = "1 2+\n  \"a\\\"b\" ()_ ' hi\nfoo";
+        let tokens: Vec<_> = Tokeniser::from_char_iter("test", src.chars().map(Ok::<_, ()>), |s| ["+", "()", "_"].contains(&s))
+            .map(Result::unwrap)
+            .filter(|(_, class, _)| !class.should_ignore())
+            .map(|(s, class, span)| (s, class, span.line, span.col, span.len))
+            .collect();
+
+        assert_eq!(tokens, vec![
+            ("1".to_owned(), Class::Number, 1, 1, 1),
+            ("2".to_owned(), Class::Number, 1, 3, 1),
+            ("+".to_owned(), Class::Operator, 1, 4, 1),
+            ("\"a\"b\"".to_owned(), Class::String, 2, 3, 6),
+            ("()".to_owned(), Class::Operator, 2, 10, 2),
+            ("_".to_owned(), Class::Operator, 2, 12, 1),
+            ("foo".to_owned(), Class::Identifier, 3, 1, 3),
+        ]);
+    }
+
+    #[test]
+    fn strings_span_lines() {
+        let tokens: Vec<_> = Tokeniser::from_char_iter("test", "\"a\nb\" \"c\nd".chars().map(Ok::<_, ()>), |_| false)
+            .map(Result::unwrap)
+            .filter(|(_, class, _)| !class.should_ignore())
+            .map(|(s, class, span)| (s, class, span.line))
+            .collect();
+
+        assert_eq!(tokens, vec![
+            ("\"a\nb\"".to_owned(), Class::String, 1),
+            ("\"c\nd".to_owned(), Class::UnclosedString, 2),
+        ]);
+    }
+}
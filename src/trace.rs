@@ -0,0 +1,138 @@
+use std::fmt::{self, Display};
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::cmd::Command;
+use crate::code::{elements, ShowElement};
+use crate::err::Error;
+use crate::span::{Span, Spanned};
+use crate::state::State;
+use crate::value::Value;
+
+/// Gets told what a program is doing while it runs, see `Program::run_traced`.
+///
+/// Every method does nothing unless implemented, so a tracer only has to
+/// implement the ones it cares about.
+#[allow(unused_variables)]
+pub trait Tracer {
+    /// Called before each command runs
+    fn before_command(&mut self, step: &Step<'_>, state: &State) {}
+    /// Called after a command has run without failing.
+    ///
+    /// A command that applies a block is done once the block has been
+    /// started, so this is called before the block runs.
+    fn after_command(&mut self, step: &Step<'_>, state: &State) {}
+    /// Called when a block, loop, list literal or included file starts
+    /// running at the given depth, the program itself being at depth 1
+    fn enter_block(&mut self, depth: usize, state: &State) {}
+    /// Called when whatever runs at the given depth is done, also when it is
+    /// stopped by `return`, `break` or an error caught by `try`
+    fn exit_block(&mut self, depth: usize, state: &State) {}
+    /// Called after `:=` or `local` has given a variable a value
+    fn define(&mut self, name: &str, state: &State) {}
+    /// Called when a command fails, before the error is given to `try`
+    fn error(&mut self, error: &Error, state: &State) {}
+}
+
+/// A command that is about to run or has just run
+pub struct Step<'a> {
+    /// The command, followed by the rest of its literal if it starts one
+    elem: &'a [Spanned<Command>],
+    depth: usize,
+}
+
+impl<'a> Step<'a> {
+    /// The command at `pc` in the code
+    pub(crate) fn at(code: &'a [Spanned<Command>], pc: usize, depth: usize) -> Self {
+        Step { elem: elements(&code[pc..]).next().unwrap(), depth }
+    }
+    /// Where the command is in the source, unless it was made while running
+    pub fn span(&self) -> Option<&Span> {
+        self.elem[0].span.as_ref()
+    }
+    /// The name of the command, or what kind of value a literal makes, like `<block>`
+    pub fn name(&self) -> &'static str {
+        self.elem[0].inner.name()
+    }
+    /// How many blocks, loops and included files deep the command is, starting at 1
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+    /// The name of the variable whose block the command applies, if it is
+    /// `apply` used on a variable name and hasn't run yet
+    pub fn applied(&self, state: &State) -> Option<Arc<str>> {
+        match (&self.elem[0].inner, state.stack().last()) {
+            (Command::ApplyFunction, Some(&Value::Variable(var))) => Some(var.name()),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Step<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", ShowElement(self.elem))
+    }
+}
+
+/// Writes each command with the stack from before it runs, indented by how
+/// deep it is
+pub struct WriteTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> WriteTracer<W> {
+    pub fn new(out: W) -> Self {
+        WriteTracer { out }
+    }
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Tracer for WriteTracer<W> {
+    fn before_command(&mut self, step: &Step<'_>, state: &State) {
+        // Tracing shouldn't stop the program, so failed writes are ignored
+        let _ = writeln!(self.out, "  {}{}: {:?}", "    ".repeat(step.depth() - 1), step, state.stack());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Step, Tracer};
+    use crate::err::Error;
+    use crate::program::compile;
+    use crate::state::State;
+    use crate::InOuter;
+
+    #[test]
+    fn tracing() {
+        struct Events(Vec<String>);
+        impl Tracer for Events {
+            fn before_command(&mut self, step: &Step<'_>, state: &State) {
+                let applied = step.applied(state).map(|name| format!(" {}", name)).unwrap_or_default();
+                self.0.push(format!("{} {}{}", step.depth(), step, applied));
+            }
+            fn enter_block(&mut self, depth: usize, _: &State) {
+                self.0.push(format!("enter {}", depth));
+            }
+            fn exit_block(&mut self, depth: usize, _: &State) {
+                self.0.push(format!("exit {}", depth));
+            }
+            fn define(&mut self, name: &str, state: &State) {
+                self.0.push(format!("{} = {:?}", name, state.lookup(name).unwrap()));
+            }
+            fn error(&mut self, error: &Error, _: &State) {
+                self.0.push(error.name().to_owned());
+            }
+        }
+        let mut io = InOuter::new(Vec::new(), &b""[..]);
+        let mut events = Events(Vec::new());
+        let program = compile(&b"{ 1 + } inc1 := 2 inc1 () { ~ ~ } { ~ } try"[..]).unwrap();
+        program.run_traced(&mut State::new(), &mut io, &mut events).unwrap();
+        let expected = [
+            "enter 1", "1 {1, +}", "1 inc1", "1 :=", "inc1 = {1, +}", "1 2", "1 inc1", "1 () inc1", "enter 2", "2 1", "2 +", "exit 2",
+            "1 {~, ~}", "1 {~}", "1 try", "enter 2", "enter 3", "3 ~", "3 ~", "EmptyStack", "exit 3", "exit 2", "enter 2", "2 ~", "exit 2", "exit 1",
+        ];
+        assert_eq!(events.0, expected);
+    }
+}
@@ -1,21 +1,74 @@
 use std::cmp::Ordering;
-use std::f64::NAN;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fmt;
+use std::mem;
 use std::ops::*;
 
+use num_bigint::{BigInt, Sign};
+use num_traits::{FromPrimitive, ToPrimitive};
+
 use crate::cmd::Command;
+use crate::code::{elements, Code, ShowElement};
+use crate::err::{Error, Result};
+use crate::span::Spanned;
+use crate::var::Var;
 
 #[derive(Clone)]
 pub enum Value {
     Float(f64),
     Integer(i64),
+    /// An integer too big for `Integer`, never used for one that would fit
+    Big(BigInt),
     Bool(bool),
     Str(String),
-    Variable(String),
-    Block(u16, Vec<Command>),
+    Variable(Var),
+    Block(u64, Code),
+    List(Vec<Value>),
+    Map(BTreeMap<Key, Value>),
     Null,
 }
 
+/// A value that can be used as a key in a map
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Key {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+impl TryFrom<Value> for Key {
+    type Error = Error;
+    fn try_from(v: Value) -> Result<Key> {
+        match v {
+            Bool(b) => Ok(Key::Bool(b)),
+            Integer(n) => Ok(Key::Int(n)),
+            Str(s) => Ok(Key::Str(s)),
+            _ => Err(Error::InvalidKey),
+        }
+    }
+}
+
+impl From<Key> for Value {
+    fn from(k: Key) -> Value {
+        match k {
+            Key::Bool(b) => Bool(b),
+            Key::Int(n) => Integer(n),
+            Key::Str(s) => Str(s),
+        }
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Bool(b) => b.fmt(f),
+            Key::Int(n) => n.fmt(f),
+            Key::Str(s) => s.fmt(f),
+        }
+    }
+}
+
 impl Value {
     pub fn parse(s: &str) -> Self {
         if s.starts_with('"') {
@@ -23,6 +76,8 @@ impl Value {
             Str(s[1..s.len() - 1].to_owned())
         } else if let Ok(n) = s.parse::<i64>() {
             Integer(n)
+        } else if let Ok(n) = s.parse::<BigInt>() {
+            Big(n)
         } else if let Ok(n) = s.parse::<f64>() {
             Float(n)
         } else if let Ok(b) = s.parse::<bool>() {
@@ -30,18 +85,20 @@ impl Value {
         } else if s == "ø" || s == "null" {
             Null
         } else {
-            Variable(s.to_owned())
+            Variable(Var::new(s))
         }
     }
 
     pub fn as_bool(&self) -> bool {
         match *self {
             Float(n) => !n.is_nan(),
-            Integer(_) => true,
+            Integer(_) | Big(_) => true,
             Str(ref s) => !s.is_empty(),
             Bool(b) => b,
             Block(_, _) => true,
-            // TODO Return error
+            List(ref l) => !l.is_empty(),
+            Map(ref m) => !m.is_empty(),
+            // Only undefined variables get here, which strict mode doesn't allow
             Variable(_) => false,
             Null => false,
         }
@@ -50,50 +107,317 @@ impl Value {
         let repl = self.as_bool();
         *self = Bool(repl);
     }
-    pub fn make_float(&mut self) {
-        let repl = match *self {
+    pub fn into_float(self) -> Result<Self> {
+        Ok(match self {
             Bool(b) => Float(f64::from(b as i8)),
-            Float(_) => return,
+            f @ Float(_) => f,
             Integer(n) => Float(n as f64),
-            Null | Block(_, _) => Float(NAN),
-            // TODO Return error
-            Variable(_) => Float(NAN),
-            Str(ref s) => Float(s.parse::<f64>().unwrap_or(NAN)),
-        };
-        *self = repl;
+            Big(ref n) => Float(n.to_f64().unwrap_or(f64::NAN)),
+            Str(ref s) => match s.parse::<f64>() {
+                Ok(n) => Float(n),
+                Err(_) => return Err(self.invalid_cast("float")),
+            },
+            v => return Err(v.invalid_cast("float")),
+        })
     }
-    pub fn make_int(&mut self) {
-        let repl = match *self {
+    pub fn into_int(self) -> Result<Self> {
+        Ok(match self {
             Bool(b) => Integer(b as i64),
-            Integer(_) => return,
+            n @ Integer(_) | n @ Big(_) => n,
+            Float(n) if n.is_finite() && (n < i64::MIN as f64 || n >= i64::MAX as f64) => BigInt::from_f64(n).map(Big).unwrap_or(Null),
             Float(n) => Integer(n as i64),
-            Null | Block(_, _) => Null,
-            // TODO Return error
-            Variable(_) => Null,
-            Str(ref s) => s.parse::<i64>().map(Integer).unwrap_or(Null),
-        };
-        *self = repl;
+            Str(ref s) => match s.parse::<BigInt>() {
+                Ok(n) => big(n),
+                Err(_) => return Err(self.invalid_cast("int")),
+            },
+            v => return Err(v.invalid_cast("int")),
+        })
     }
-    pub fn flatten(self) -> Self {
-        match self {
+    pub fn into_list(self) -> Result<Self> {
+        Ok(match self.flatten()? {
+            List(l) => List(l),
+            Block(_, b) => {
+                let mut l = allocate(elements(&b).count())?;
+                l.extend(b.pieces().map(|piece| match *piece {
+                    [Spanned { inner: Command::Value(ref v), .. }] => v.clone(),
+                    [Spanned { inner: Command::Quote(_), .. }, ..] => Block(1, piece.slice(1, piece.len())),
+                    _ => Block(1, piece),
+                }));
+                List(l)
+            }
+            Str(s) => List(s.chars().map(|c| Str(c.to_string())).collect()),
+            v => return Err(v.invalid_cast("list")),
+        })
+    }
+    pub fn into_block(self) -> Result<Self> {
+        Ok(match self {
+            List(l) => Block(1, l.into_iter().map(|v| Spanned::bare(Command::Value(v))).collect::<Vec<_>>().into()),
+            b @ Block(_, _) => b,
+            v => return Err(v.invalid_cast("block")),
+        })
+    }
+    /// Turns a list of alternating keys and values into a map
+    pub fn into_map(self) -> Result<Self> {
+        Ok(match self {
+            List(l) => {
+                if l.len() % 2 != 0 {
+                    return Err(Error::InvalidMapArg);
+                }
+                let mut map = BTreeMap::new();
+                let mut l = l.into_iter();
+                while let (Some(k), Some(v)) = (l.next(), l.next()) {
+                    map.insert(Key::try_from(k)?, v);
+                }
+                Map(map)
+            }
+            m @ Map(_) => m,
+            v => return Err(v.invalid_cast("map")),
+        })
+    }
+    fn invalid_cast(&self, to: &'static str) -> Error {
+        Error::InvalidCast { from: self.type_name(), to }
+    }
+    pub fn flatten(self) -> Result<Self> {
+        Ok(match self {
+            Block(1, b) => Block(1, b),
             Block(n, b) => {
-                let len = n as usize * b.len();
-                let b = b.into_iter().cycle().take(len).collect();
+                let len = repeated_len::<Spanned<Command>>(n, b.len())?;
+                let mut flat = allocate(len)?;
+                flat.extend(b.iter().cycle().take(len).cloned());
 
-                Block(1, b)
+                Block(1, flat.into())
             }
             a => a,
+        })
+    }
+    pub fn pow(self, rhs: Self) -> Result<Self> {
+        Ok(match (self, rhs) {
+            (Integer(a), Integer(b)) if (0..=u32::MAX as i64).contains(&b) => {
+                a.checked_pow(b as u32).map(Integer).unwrap_or_else(|| Big(BigInt::from(a).pow(b as u32)))
+            }
+            (Big(a), Integer(b)) if (0..=u32::MAX as i64).contains(&b) => big(a.pow(b as u32)),
+            (Float(a), Integer(b)) if (i32::MIN as i64..=i32::MAX as i64).contains(&b) => Float(a.powi(b as i32)),
+            (a, b) if a.is_number() && b.is_number() => Float(a.to_f64().powf(b.to_f64())),
+            (a, b) => return Err(Error::type_error("**", &a, Some(&b))),
+        })
+    }
+    /// The name of the type of the value, as pushed by `type`
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Float(_) => "float",
+            Integer(_) | Big(_) => "int",
+            Bool(_) => "bool",
+            Str(_) => "str",
+            Variable(_) => "var",
+            Block(_, _) => "block",
+            List(_) => "list",
+            Map(_) => "map",
+            Null => "null",
+        }
+    }
+    pub(crate) fn is_collection(&self) -> bool {
+        matches!(self, Block(_, _) | List(_) | Map(_))
+    }
+    fn is_integer(&self) -> bool {
+        matches!(self, Integer(_) | Big(_))
+    }
+    fn is_number(&self) -> bool {
+        matches!(self, Integer(_) | Big(_) | Float(_))
+    }
+    /// The value of an `Integer` or `Big` as a big integer
+    fn into_big(self) -> BigInt {
+        match self {
+            Integer(n) => BigInt::from(n),
+            Big(n) => n,
+            _ => unreachable!("only called on integers"),
+        }
+    }
+    /// The value of a number as a float
+    fn to_f64(&self) -> f64 {
+        match *self {
+            Integer(n) => n as f64,
+            Big(ref n) => n.to_f64().unwrap_or(f64::NAN),
+            Float(n) => n,
+            _ => unreachable!("only called on numbers"),
+        }
+    }
+    /// How long a string, block, list or map is, strings and big integers
+    /// counting bytes and repeated blocks counting every repetition, or 0 for
+    /// anything else
+    pub(crate) fn size(&self) -> u64 {
+        match *self {
+            Big(ref b) => bytes(b.bits()),
+            Str(ref s) => s.len() as u64,
+            Block(n, ref b) => n.saturating_mul(b.len() as u64),
+            List(ref l) => l.len() as u64,
+            Map(ref m) => m.len() as u64,
+            _ => 0,
+        }
+    }
+    /// How many bits an integer takes without its sign
+    fn bits(&self) -> Option<u64> {
+        match *self {
+            Integer(n) => Some(u64::from(64 - n.unsigned_abs().leading_zeros())),
+            Big(ref b) => Some(b.bits()),
+            _ => None,
+        }
+    }
+    /// At most how big adding the values makes the result, as in `size`
+    pub(crate) fn sum_size(&self, rhs: &Self) -> u64 {
+        match (self.bits(), rhs.bits()) {
+            (Some(a), Some(b)) => bytes(a.max(b) + 1),
+            _ => self.size().saturating_add(rhs.size()),
         }
     }
-    pub fn pow(self, rhs: Self) -> Self {
+    /// At most how big multiplying the values makes the result, as in `size`
+    pub(crate) fn product_size(&self, rhs: &Self) -> u64 {
+        if let (Some(a), Some(b)) = (self.bits(), rhs.bits()) {
+            return bytes(a + b);
+        }
         match (self, rhs) {
-            (Integer(a), Integer(b)) => Integer(a.pow(b as u32)),
-            (Integer(a), Float(b)) => Float((a as f64).powf(b)),
-            (Float(a), Integer(b)) => Float(a.powi(b as i32)),
-            (Float(a), Float(b)) => Float(a.powf(b)),
-            _ => Null,
+            (&Integer(n), v) | (v, &Integer(n)) => v.size().saturating_mul(n.max(0) as u64),
+            (Big(_), v) | (v, Big(_)) if v.size() > 0 => u64::MAX,
+            _ => 0,
         }
     }
+    /// At most how big raising the value to the power makes the result, as in `size`
+    pub(crate) fn power_size(&self, rhs: &Self) -> u64 {
+        let log = match *self {
+            Integer(a) => (a.unsigned_abs() as f64).log2(),
+            Big(ref a) => a.bits() as f64,
+            _ => return 0,
+        };
+        match *rhs {
+            // 0, 1 and -1 stay as small, and other exponents give floats
+            Integer(n) if log > 0. && (0..=u32::MAX as i64).contains(&n) => bytes((log * n as f64) as u64 + 1),
+            _ => 0,
+        }
+    }
+    /// Source code that pushes this value when run, if it can be written down.
+    ///
+    /// Strings with quotation marks in them can't, as strings have no escaping.
+    pub fn to_source(&self) -> Option<String> {
+        let mut src = String::new();
+        write_source(self, &mut src)?;
+        Some(src)
+    }
+}
+
+/// Writes source code that pushes the value, see `Value::to_source`
+fn write_source(v: &Value, src: &mut String) -> Option<()> {
+    match *v {
+        // There are no negative literals, so they are subtracted from zero
+        Integer(n) if n < 0 => src.push_str(&format!("0 {} -", n.unsigned_abs())),
+        Big(ref n) if n.sign() == Sign::Minus => src.push_str(&format!("0 {} -", -n)),
+        Float(n) if n < 0. => src.push_str(&format!("0 {} -", Float(-n).to_source()?)),
+        Float(n) => {
+            src.push_str(&n.to_string());
+            // Whole numbers would be read back as integers otherwise
+            if n.is_finite() && n.fract() == 0. {
+                src.push_str(".0");
+            }
+        }
+        Integer(n) => src.push_str(&n.to_string()),
+        Big(ref n) => src.push_str(&n.to_string()),
+        Bool(b) => src.push_str(&b.to_string()),
+        Null => src.push_str("null"),
+        Str(ref s) if s.contains('"') => return None,
+        Str(ref s) => src.push_str(&format!("\"{}\"", s)),
+        Block(n, ref b) => {
+            src.push_str(if b.env().is_some() { "^{" } else { "{" });
+            write_code(b, src)?;
+            src.push_str(" }");
+            if n != 1 {
+                src.push_str(&format!(" {} *", n));
+            }
+        }
+        List(ref l) => {
+            src.push('(');
+            for v in l {
+                src.push(' ');
+                write_source(v, src)?;
+            }
+            src.push_str(" )");
+        }
+        Map(ref m) => {
+            src.push('(');
+            for (k, v) in m {
+                src.push(' ');
+                write_source(&k.clone().into(), src)?;
+                src.push(' ');
+                write_source(v, src)?;
+            }
+            src.push_str(" ) map");
+        }
+        Variable(var) => src.push_str(&var.name()),
+    }
+    Some(())
+}
+
+/// Writes the commands of a block as source code, each preceded by a space
+fn write_code(code: &[Spanned<Command>], src: &mut String) -> Option<()> {
+    for elem in elements(code) {
+        src.push(' ');
+        let (open, close) = match elem[0].inner {
+            Command::Quote(_) => ("{", " }"),
+            Command::Capture(_) => ("^{", " }"),
+            Command::Collect(_) => ("(", " )"),
+            Command::Value(ref v) => {
+                write_source(v, src)?;
+                continue;
+            }
+            ref cmd => {
+                src.push_str(&format!("{:?}", cmd));
+                continue;
+            }
+        };
+        src.push_str(open);
+        write_code(&elem[1..], src)?;
+        src.push_str(close);
+    }
+    Some(())
+}
+
+/// Makes an integer value, only using `Big` when it doesn't fit in an `i64`
+fn big(n: BigInt) -> Value {
+    n.to_i64().map(Integer).unwrap_or(Big(n))
+}
+
+/// How many bytes it takes to hold the bits
+fn bytes(bits: u64) -> u64 {
+    bits.div_ceil(8)
+}
+
+/// How many times to repeat something multiplied by the integer `n`
+fn repeat_count(n: &Value) -> Result<u64> {
+    match *n {
+        Integer(n) if n < 0 => Err(Error::NegativeRepeat),
+        Integer(n) => Ok(n as u64),
+        Big(ref n) if n.sign() == Sign::Minus => Err(Error::NegativeRepeat),
+        Big(ref n) => n.to_u64().ok_or(Error::RepeatOverflow),
+        _ => unreachable!("only called with integers"),
+    }
+}
+
+/// The length of `len` things of type `T` repeated `n` times, if that many can be allocated
+fn repeated_len<T>(n: u64, len: usize) -> Result<usize> {
+    let max = isize::MAX as usize / mem::size_of::<T>().max(1);
+    usize::try_from(n).ok().and_then(|n| n.checked_mul(len)).filter(|&len| len <= max).ok_or(Error::RepeatOverflow)
+}
+
+/// An empty vector with room for `len` things, failing with `RepeatOverflow`
+/// instead of aborting if there isn't enough memory for them
+fn allocate<T>(len: usize) -> Result<Vec<T>> {
+    let mut v = Vec::new();
+    v.try_reserve_exact(len).map_err(|_| Error::RepeatOverflow)?;
+    Ok(v)
+}
+
+impl From<BigInt> for Value {
+    #[inline(always)]
+    fn from(n: BigInt) -> Value {
+        big(n)
+    }
 }
 
 impl From<bool> for Value {
@@ -128,9 +452,14 @@ impl PartialEq for Value {
             (&Float(a), &Float(b)) => a == b,
             (&Integer(a), &Integer(b)) => a == b,
             (&Integer(a), &Float(b)) | (&Float(b), &Integer(a)) => a as f64 == b,
+            (Big(a), Big(b)) => a == b,
+            (Big(a), &Integer(b)) | (&Integer(b), Big(a)) => *a == BigInt::from(b),
+            (a @ Big(_), &Float(b)) | (&Float(b), a @ Big(_)) => a.to_f64() == b,
             (&Bool(a), &Bool(b)) => a == b,
-            (&Str(ref a), &Str(ref b)) => a == b,
+            (Str(a), Str(b)) => a == b,
             (&Block(n, ref a), &Block(m, ref b)) => a == b && n == m,
+            (List(a), List(b)) => a == b,
+            (Map(a), Map(b)) => a == b,
             (&Variable(_), _) | (_, &Variable(_)) => false,
             _ => false
         }
@@ -142,9 +471,16 @@ impl PartialOrd for Value {
         match (self, other) {
             (Float(a), Float(b)) => a.partial_cmp(b),
             (Integer(a), Integer(b)) => a.partial_cmp(b),
-            (&Integer(a), &Float(b)) | (&Float(b), &Integer(a)) => (a as f64).partial_cmp(&b),
+            (&Integer(a), &Float(b)) => (a as f64).partial_cmp(&b),
+            (&Float(a), &Integer(b)) => a.partial_cmp(&(b as f64)),
+            (Big(a), Big(b)) => a.partial_cmp(b),
+            (Big(a), &Integer(b)) => a.partial_cmp(&BigInt::from(b)),
+            (&Integer(a), Big(b)) => BigInt::from(a).partial_cmp(b),
+            (a @ Big(_), &Float(b)) => a.to_f64().partial_cmp(&b),
+            (&Float(a), b @ Big(_)) => a.partial_cmp(&b.to_f64()),
             (Bool(a), Bool(b)) => a.partial_cmp(b),
             (Str(ref a), Str(ref b)) => a.partial_cmp(b),
+            (List(a), List(b)) => a.partial_cmp(b),
             _ => None,
         }
     }
@@ -157,10 +493,13 @@ impl fmt::Display for Value {
         match *self {
             Float(ref n) => n.fmt(f),
             Integer(ref n) => n.fmt(f),
+            Big(ref n) => n.fmt(f),
             Bool(ref n) => n.fmt(f),
             Str(ref s) => s.fmt(f),
             Block(_, _) => write!(f, "[code block]"),
-            Variable(ref s) => write!(f, "[variable: {}]", s),
+            List(ref l) => fmt_list(f, l),
+            Map(ref m) => f.debug_map().entries(m).finish(),
+            Variable(s) => write!(f, "[variable: {}]", s),
             Null => "NULL".fmt(f),
         }
     }
@@ -171,154 +510,245 @@ impl fmt::Debug for Value {
         match *self {
             Float(ref n) => n.fmt(f),
             Integer(ref n) => n.fmt(f),
+            Big(ref n) => fmt::Display::fmt(n, f),
             Bool(ref n) => n.fmt(f),
             Str(ref s) => s.fmt(f),
             Null => write!(f, "null"),
             Block(n, ref b) => {
-                let mut dbg = f.debug_set();
-                for _ in 0..n {
-                    dbg.entries(b);
+                if b.env().is_some() {
+                    write!(f, "^")?;
                 }
-                dbg.finish()
+                f.debug_set().entries(elements(b).map(ShowElement)).finish()?;
+                if n != 1 {
+                    write!(f, " * {}", n)?;
+                }
+                Ok(())
             }
-            Variable(ref s) => write!(f, "{}", s),
+            List(ref l) => fmt_list(f, l),
+            Map(ref m) => f.debug_map().entries(m).finish(),
+            Variable(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Debug formats some items the way lists are written, like `(1, 2, 3)`
+pub(crate) fn fmt_list<I: IntoIterator>(f: &mut fmt::Formatter<'_>, items: I) -> fmt::Result
+where
+    I::Item: fmt::Debug,
+{
+    write!(f, "(")?;
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
         }
+        fmt::Debug::fmt(&item, f)?;
     }
+    write!(f, ")")
 }
 
 impl Not for Value {
-    type Output = Self;
-    fn not(self) -> Self {
-        match self {
+    type Output = Result<Self>;
+    fn not(self) -> Result<Self> {
+        Ok(match self {
             Bool(b) => Bool(!b),
             Integer(n) => Integer(!n),
-            Null | Block(_, _) | Variable(_) => Null,
+            Big(n) => big(!n),
             s @ Float(_) | s @ Str(_) => Bool(!s.as_bool()),
-        }
+            a => return Err(Error::type_error("!", &a, None)),
+        })
     }
 }
 
 impl BitAnd for Value {
-    type Output = Self;
-    fn bitand(self, other: Self) -> Self {
-        match (self, other) {
+    type Output = Result<Self>;
+    fn bitand(self, other: Self) -> Result<Self> {
+        Ok(match (self, other) {
             (Integer(a), Integer(b)) => Integer(a & b),
+            (a, b) if a.is_integer() && b.is_integer() => big(a.into_big() & b.into_big()),
             (Bool(a), Bool(b)) => Bool(a && b),
-            (Block(_, _), _) | (_, Block(_, _)) => Null,
+            (a, b) if a.is_collection() || b.is_collection() => return Err(Error::type_error("&", &a, Some(&b))),
             (a, b) => (a.as_bool() && b.as_bool()).into(),
-        }
+        })
     }
 }
 
 impl BitOr for Value {
-    type Output = Self;
-    fn bitor(self, other: Self) -> Self {
-        match (self, other) {
+    type Output = Result<Self>;
+    fn bitor(self, other: Self) -> Result<Self> {
+        Ok(match (self, other) {
             (Integer(a), Integer(b)) => Integer(a | b),
+            (a, b) if a.is_integer() && b.is_integer() => big(a.into_big() | b.into_big()),
             (Bool(a), Bool(b)) => Bool(a || b),
-            (Block(_, _), _) | (_, Block(_, _)) => Null,
+            (a, b) if a.is_collection() || b.is_collection() => return Err(Error::type_error("|", &a, Some(&b))),
             (a, b) => (a.as_bool() || b.as_bool()).into(),
-        }
+        })
     }
 }
 
 impl BitXor for Value {
-    type Output = Self;
-    fn bitxor(self, other: Self) -> Self {
-        match (self, other) {
+    type Output = Result<Self>;
+    fn bitxor(self, other: Self) -> Result<Self> {
+        Ok(match (self, other) {
             (Integer(a), Integer(b)) => Integer(a ^ b),
+            (a, b) if a.is_integer() && b.is_integer() => big(a.into_big() ^ b.into_big()),
             (Bool(a), Bool(b)) => Bool(a ^ b),
-            (Block(_, _), _) | (_, Block(_, _)) => Null,
+            (a, b) if a.is_collection() || b.is_collection() => return Err(Error::type_error("^", &a, Some(&b))),
             (a, b) => (a.as_bool() ^ b.as_bool()).into(),
-        }
+        })
     }
 }
 
 impl Add for Value {
-    type Output = Self;
-    fn add(self, other: Self) -> Self {
-        match (self, other) {
+    type Output = Result<Self>;
+    fn add(self, other: Self) -> Result<Self> {
+        Ok(match (self, other) {
             (Null, b) => b,
             (Str(a), b) => Str(format!("{}{}", a, b)),
             (a, Null) => a,
-            (Float(a), Str(b)) => Str(format!("{}{}", a, b)),
-            (Integer(a), Str(b)) => Str(format!("{}{}", a, b)),
-            (Integer(a), Integer(b)) => Integer(a + b),
-            (Integer(a), Float(b)) | (Float(b), Integer(a)) => Float(a as f64 + b),
-            (Float(a), Float(b)) => Float(a + b),
-            (Block(1, mut a), Block(1, b)) => {
-                a.extend(b);
-                Block(1, a)
+            (a, Str(b)) if a.is_number() => Str(format!("{}{}", a, b)),
+            (Integer(a), Integer(b)) => a.checked_add(b).map(Integer).unwrap_or_else(|| Big(BigInt::from(a) + b)),
+            (a, b) if a.is_integer() && b.is_integer() => big(a.into_big() + b.into_big()),
+            (Float(a), b) if b.is_number() => Float(a + b.to_f64()),
+            (a, Float(b)) if a.is_number() => Float(a.to_f64() + b),
+            (Block(1, a), Block(1, b)) => {
+                let mut a = a.into_vec();
+                a.extend(b.iter().cloned());
+                Block(1, a.into())
             }
             (Block(an, a), Block(bn, b)) => {
                 if a == b {
-                    Block(an + bn, a)
+                    Block(an.checked_add(bn).ok_or(Error::RepeatOverflow)?, a)
                 } else {
-                    let mut res = Vec::with_capacity(an as usize * a.len() + bn as usize * b.len());
+                    let a_len = repeated_len::<Spanned<Command>>(an, a.len())?;
+                    let b_len = repeated_len::<Spanned<Command>>(bn, b.len())?;
+                    let mut res = allocate(repeated_len::<Spanned<Command>>(1, a_len + b_len)?)?;
 
-                    for _ in 0..an {
-                        res.extend(a.iter().cloned())
-                    }
-                    for _ in 0..bn {
-                        res.extend(b.iter().cloned())
-                    }
-                    Block(1, res)
+                    res.extend(a.iter().cycle().take(a_len).cloned());
+                    res.extend(b.iter().cycle().take(b_len).cloned());
+                    Block(1, res.into())
                 }
             }
-            _ => Null,
-        }
+            (List(mut a), List(b)) => {
+                a.extend(b);
+                List(a)
+            }
+            (a, b) => return Err(Error::type_error("+", &a, Some(&b))),
+        })
     }
 }
 
 impl Mul for Value {
-    type Output = Self;
-    fn mul(self, other: Self) -> Self {
-        match (self, other) {
-            (Str(s), Integer(n)) | (Integer(n), Str(s)) => Str(s.repeat(n as usize)),
-            (Integer(a), Integer(b)) => Integer(a * b),
-            (Integer(a), Float(b)) | (Float(b), Integer(a)) => Float(a as f64 * b),
-            (Float(a), Float(b)) => Float(a * b),
-            (Integer(n), Block(bn, b)) | (Block(bn, b), Integer(n)) => Block(n as u16 * bn, b),
-            _ => Null,
-        }
+    type Output = Result<Self>;
+    fn mul(self, other: Self) -> Result<Self> {
+        Ok(match (self, other) {
+            (Str(s), n) | (n, Str(s)) if n.is_integer() => {
+                let n = repeat_count(&n)?;
+                // Makes sure the repeated string fits before making it
+                let len = repeated_len::<u8>(n, s.len())?;
+                let mut repeated = String::new();
+                repeated.try_reserve_exact(len).map_err(|_| Error::RepeatOverflow)?;
+                if !s.is_empty() {
+                    for _ in 0..n {
+                        repeated.push_str(&s);
+                    }
+                }
+                Str(repeated)
+            }
+            (Integer(a), Integer(b)) => a.checked_mul(b).map(Integer).unwrap_or_else(|| Big(BigInt::from(a) * b)),
+            (a, b) if a.is_integer() && b.is_integer() => big(a.into_big() * b.into_big()),
+            (Float(a), b) if b.is_number() => Float(a * b.to_f64()),
+            (a, Float(b)) if a.is_number() => Float(a.to_f64() * b),
+            (n, Block(bn, b)) | (Block(bn, b), n) if n.is_integer() => {
+                Block(repeat_count(&n)?.checked_mul(bn).ok_or(Error::RepeatOverflow)?, b)
+            }
+            (a, b) => return Err(Error::type_error("*", &a, Some(&b))),
+        })
     }
 }
 
 impl Sub for Value {
-    type Output = Self;
-    fn sub(self, other: Self) -> Self {
-        match (self, other) {
-            (Integer(a), Integer(b)) => Integer(a - b),
-            (Float(a), Integer(b)) => Float(a - b as f64),
-            (Integer(a), Float(b)) => Float(a as f64 - b),
-            (Float(a), Float(b)) => Float(a - b),
-            _ => Null,
-        }
+    type Output = Result<Self>;
+    fn sub(self, other: Self) -> Result<Self> {
+        Ok(match (self, other) {
+            (Integer(a), Integer(b)) => a.checked_sub(b).map(Integer).unwrap_or_else(|| Big(BigInt::from(a) - b)),
+            (a, b) if a.is_integer() && b.is_integer() => big(a.into_big() - b.into_big()),
+            (Float(a), b) if b.is_number() => Float(a - b.to_f64()),
+            (a, Float(b)) if a.is_number() => Float(a.to_f64() - b),
+            (a, b) => return Err(Error::type_error("-", &a, Some(&b))),
+        })
     }
 }
 
 impl Div for Value {
-    type Output = Self;
-    fn div(self, other: Self) -> Self {
-        match (self, other) {
-            (Integer(a), Integer(b)) => Integer(a / b),
-            (Float(a), Integer(b)) => Float(a / b as f64),
-            (Integer(a), Float(b)) => Float(a as f64 / b),
-            (Float(a), Float(b)) => Float(a / b),
-            _ => Null,
-        }
+    type Output = Result<Self>;
+    fn div(self, other: Self) -> Result<Self> {
+        Ok(match (self, other) {
+            (a, Integer(0)) if a.is_integer() => return Err(Error::DivisionByZero),
+            (Integer(a), Integer(b)) => a.checked_div(b).map(Integer).unwrap_or_else(|| Big(BigInt::from(a) / b)),
+            (a, b) if a.is_integer() && b.is_integer() => big(a.into_big() / b.into_big()),
+            (Float(a), b) if b.is_number() => Float(a / b.to_f64()),
+            (a, Float(b)) if a.is_number() => Float(a.to_f64() / b),
+            (a, b) => return Err(Error::type_error("/", &a, Some(&b))),
+        })
     }
 }
 
 impl Rem for Value {
-    type Output = Self;
-    fn rem(self, other: Self) -> Self {
-        match (self, other) {
-            (Integer(a), Integer(b)) => Integer(a % b),
-            (Float(a), Integer(b)) => Float(a % b as f64),
-            (Integer(a), Float(b)) => Float(a as f64 % b),
-            (Float(a), Float(b)) => Float(a % b),
-            _ => Null,
+    type Output = Result<Self>;
+    fn rem(self, other: Self) -> Result<Self> {
+        Ok(match (self, other) {
+            (a, Integer(0)) if a.is_integer() => return Err(Error::DivisionByZero),
+            (Integer(a), Integer(b)) => Integer(a.wrapping_rem(b)),
+            (a, b) if a.is_integer() && b.is_integer() => big(a.into_big() % b.into_big()),
+            (Float(a), b) if b.is_number() => Float(a % b.to_f64()),
+            (a, Float(b)) if a.is_number() => Float(a.to_f64() % b),
+            (a, b) => return Err(Error::type_error("%", &a, Some(&b))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Value::{self, *};
+    use crate::cmd::Command;
+    use crate::err::Error;
+    use crate::span::Spanned;
+
+    #[test]
+    fn integers_promote_on_overflow() {
+        let max = Integer(i64::MAX);
+        let sum = (max.clone() + Integer(1)).unwrap();
+        assert_eq!(sum.to_string(), "9223372036854775808");
+        assert!(sum.clone() > max);
+        assert!(matches!(sum - Integer(1), Ok(Integer(n)) if n == i64::MAX));
+
+        let fact = (1..=25).map(Integer).try_fold(Integer(1), |a, b| a * b).unwrap();
+        assert_eq!(fact.to_string(), "15511210043330985984000000");
+        assert!(matches!(Integer(2).pow(Integer(-1)), Ok(Float(n)) if n == 0.5));
+        assert!(matches!(Value::parse("99999999999999999999"), Big(_)));
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        assert!(matches!(Integer(1) / Integer(0), Err(Error::DivisionByZero)));
+        assert!(matches!(Integer(1) % Integer(0), Err(Error::DivisionByZero)));
+        assert!(matches!(Float(1.) / Integer(0), Ok(Float(n)) if n.is_infinite()));
+        match Str("a".to_owned()) - Integer(2) {
+            Err(Error::TypeError { op: "-", lhs_type: "str", rhs_type: Some("int") }) => (),
+            r => panic!("expected a type error, got {:?}", r),
         }
+        assert!(matches!(!List(Vec::new()), Err(Error::TypeError { op: "!", rhs_type: None, .. })));
+    }
+
+    #[test]
+    fn large_repeats() {
+        let block = || Block(1, vec![Spanned::bare(Command::Value(Integer(1)))].into());
+        let many = (block() * Integer(i64::MAX)).unwrap();
+        assert!(matches!((many.clone() * Integer(2)).unwrap(), Block(n, _) if n == i64::MAX as u64 * 2));
+        assert!(matches!(many.clone() * Integer(3), Err(Error::RepeatOverflow)));
+        assert!(matches!(block() * Integer(-1), Err(Error::NegativeRepeat)));
+        assert!(matches!(Str("ab".to_owned()) * Integer(-2), Err(Error::NegativeRepeat)));
+        assert!(matches!(Str("ab".to_owned()) * Integer(i64::MAX), Err(Error::RepeatOverflow)));
+        assert!(matches!(many.flatten(), Err(Error::RepeatOverflow)));
     }
 }
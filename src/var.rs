@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// A variable name resolved to a slot number when parsed.
+///
+/// The same name always gets the same slot, so a `State` can keep its
+/// variables in a plain vector indexed by the slot. Names are never forgotten,
+/// so every different name used takes up memory until the process ends, see
+/// `Limits::names`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Var(u32);
+
+#[derive(Default)]
+struct Names {
+    names: Vec<Arc<str>>,
+    slots: HashMap<Arc<str>, u32>,
+}
+
+/// The names of every slot, shared by every thread so programs and states
+/// can be sent between them
+static NAMES: Mutex<Option<Names>> = Mutex::new(None);
+
+/// The names, which nothing else can use until they're given back
+fn names() -> MutexGuard<'static, Option<Names>> {
+    // Names are only ever added after they're complete, so a panic while
+    // holding them can't leave them half changed
+    NAMES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Var {
+    pub fn new(name: &str) -> Self {
+        let mut names = names();
+        let names = names.get_or_insert_with(Names::default);
+        if let Some(&slot) = names.slots.get(name) {
+            return Var(slot);
+        }
+        let slot = names.names.len() as u32;
+        let name: Arc<str> = name.into();
+        names.names.push(name.clone());
+        names.slots.insert(name, slot);
+        Var(slot)
+    }
+    /// The variable given the slot by `Var::new`
+    pub(crate) fn from_slot(slot: usize) -> Self {
+        Var(slot as u32)
+    }
+    #[inline(always)]
+    pub fn slot(self) -> usize {
+        self.0 as usize
+    }
+    pub fn name(self) -> Arc<str> {
+        names().as_ref().expect("only made by `Var::new`").names[self.slot()].clone()
+    }
+    /// How many different names have been given slots
+    pub(crate) fn count() -> usize {
+        names().as_ref().map_or(0, |names| names.names.len())
+    }
+}
+
+impl Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&*self.name(), f)
+    }
+}
+
+impl Debug for Var {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
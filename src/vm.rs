@@ -0,0 +1,467 @@
+use std::io::{Read, Write};
+use std::vec;
+
+use crate::cmd::Command;
+use crate::code::Code;
+use crate::err::*;
+use crate::program::Program;
+use crate::span::Span;
+use crate::state::State;
+use crate::trace::{Step, Tracer};
+use crate::value::Value;
+use crate::InOuter;
+
+/// How running some code ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The code ran until its end
+    Done,
+    /// `exit` was run with the given exit code
+    Exit(i32),
+}
+
+/// What the interpreter loop should do after a command has run
+pub(crate) enum Flow {
+    Next,
+    /// Run the block the given number of times
+    Call(u64, Code),
+    /// Like `Call`, but for a block applied through a variable name
+    CallFunction(u64, Code),
+    /// Run an included file
+    Include(Program),
+    /// Run the code and collect what it pushes into a list
+    Collect(Code),
+    /// Run a loop or `cond`
+    Control(Control),
+    /// Run the body, and the handler if the body fails
+    Try { body: (u64, Code), handler: (u64, Code) },
+    /// Stop running the program with the given exit code
+    Exit(i32),
+    /// Stop running the innermost applied block
+    Return,
+    /// Stop the innermost loop
+    Break,
+    /// Go on to the next iteration of the innermost loop
+    Continue,
+}
+
+/// A loop or `cond` deciding which block to run next
+#[derive(Debug)]
+pub(crate) enum Control {
+    While {
+        cond: (u64, Code),
+        body: (u64, Code),
+        /// Whether the condition is what ran last
+        checking: bool,
+    },
+    Times {
+        body: (u64, Code),
+        left: u64,
+    },
+    Each {
+        body: (u64, Code),
+        items: vec::IntoIter<Value>,
+    },
+    Cond {
+        /// The remaining conditions, each followed by its branch
+        arms: vec::IntoIter<Value>,
+        /// The branch of the condition that ran last
+        pending: Option<Value>,
+    },
+}
+
+impl Control {
+    fn is_loop(&self) -> bool {
+        !matches!(self, Control::Cond { .. })
+    }
+    /// The block to run next, or `None` when done
+    fn step(&mut self, state: &mut State) -> Result<Option<(u64, Code)>> {
+        match self {
+            Control::While { cond, body, checking } => {
+                *checking = !*checking;
+                if *checking {
+                    Ok(Some(cond.clone()))
+                } else if state.pop()?.as_bool() {
+                    Ok(Some(body.clone()))
+                } else {
+                    Ok(None)
+                }
+            }
+            Control::Times { body, left } => {
+                if *left == 0 {
+                    return Ok(None);
+                }
+                *left -= 1;
+                Ok(Some(body.clone()))
+            }
+            Control::Each { body, items } => match items.next() {
+                Some(item) => {
+                    state.push(item);
+                    Ok(Some(body.clone()))
+                }
+                None => Ok(None),
+            },
+            Control::Cond { arms, pending } => loop {
+                if let Some(branch) = pending.take() {
+                    if state.pop()?.as_bool() {
+                        *arms = Vec::new().into_iter();
+                        return Ok(Control::branch(branch, state));
+                    }
+                }
+                let (cond, branch) = match (arms.next(), arms.next()) {
+                    (Some(cond), Some(branch)) => (cond, branch),
+                    _ => return Ok(None),
+                };
+                *pending = Some(branch);
+                match cond {
+                    Value::Block(n, cond) => return Ok(Some((n, cond))),
+                    cond => state.push(cond),
+                }
+            },
+        }
+    }
+    /// Runs a branch of `cond`, which is pushed if it isn't a block
+    fn branch(branch: Value, state: &mut State) -> Option<(u64, Code)> {
+        match branch {
+            Value::Block(n, b) => Some((n, b)),
+            v => {
+                state.push(v);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum FrameKind {
+    /// The code the interpreter was started with
+    Top,
+    /// A block run by `apply`, or by a loop or `cond`
+    Block,
+    /// A block run by `apply` through a variable name, which `return` leaves
+    Function,
+    /// A file run by `inc`
+    Include,
+    /// The body of a list literal, which started at the given stack height
+    Collect(usize),
+    /// A loop or `cond`, which runs its blocks in frames on top of it
+    Control(Control),
+    /// A `try`, which runs its body in a frame on top of it
+    Try {
+        handler: (u64, Code),
+        /// The number of scopes open when the `try` started
+        depth: usize,
+    },
+}
+
+/// Some code being run and how far it has gotten
+struct Frame {
+    code: Code,
+    pc: usize,
+    /// How many times the code still has to be run, including the current one
+    reps: u64,
+    kind: FrameKind,
+    /// Where the frame was started from
+    span: Option<Span>,
+}
+
+impl Frame {
+    fn new(code: Code, reps: u64, kind: FrameKind, span: Option<Span>) -> Self {
+        Frame { code, pc: 0, reps, kind, span }
+    }
+    /// Cleans up after the frame is done running
+    fn finish(self, state: &mut State) -> Result<()> {
+        match self.kind {
+            FrameKind::Block | FrameKind::Function => {
+                if let Some(env) = self.code.env() {
+                    for (var, val) in env.lock().iter_mut() {
+                        if let Some(v) = state.current_local(*var) {
+                            *val = v.clone();
+                        }
+                    }
+                }
+                state.pop_scope()
+            }
+            FrameKind::Collect(height) => {
+                let list = state.take_from(height).map_err(|e| e.at(self.span.as_ref()))?;
+                state.push(Value::List(list));
+            }
+            FrameKind::Try { .. } => state.exit_try(),
+            FrameKind::Top | FrameKind::Include | FrameKind::Control(_) => (),
+        }
+        Ok(())
+    }
+}
+
+/// Starts running a block in a scope of its own
+fn call(frames: &mut Vec<Frame>, state: &mut State, reps: u64, block: Code, kind: FrameKind, span: Option<Span>) {
+    if reps == 0 {
+        return;
+    }
+    state.push_scope();
+    if let Some(env) = block.env() {
+        for (var, val) in env.lock().iter() {
+            state.add_local(*var, val.clone());
+        }
+    }
+    frames.push(Frame::new(block, reps, kind, span))
+}
+
+/// Runs the code until it's done.
+///
+/// Blocks get their own frame on an explicit call stack instead of
+/// recursing, so deeply nested applications don't use up the Rust stack.
+pub(crate) fn run<W: Write, R: Read>(code: Code, state: &mut State, io: &mut InOuter<W, R>, tracer: Option<&mut dyn Tracer>) -> Result<Status> {
+    // Programs can only add names when compiled, so they are counted before running
+    state.check_names()?;
+    let depth = state.scope_depth();
+    let tries = state.tries();
+    let res = run_frames(code, state, io, tracer);
+    // Scopes and `try`s stopped by an error would otherwise stay open
+    state.truncate_scopes(depth);
+    state.set_tries(tries);
+    res
+}
+
+fn run_frames<W: Write, R: Read>(code: Code, state: &mut State, io: &mut InOuter<W, R>, mut tracer: Option<&mut dyn Tracer>) -> Result<Status> {
+    let mut frames = vec![Frame::new(code, 1, FrameKind::Top, None)];
+    if let Some(ref mut tracer) = tracer {
+        tracer.enter_block(1, state);
+    }
+
+    loop {
+        let res = match tracer {
+            Some(ref mut tracer) => traced_step(&mut frames, state, io, &mut **tracer),
+            None => run_steps(&mut frames, state, io),
+        };
+        match res {
+            Ok(Some(status)) => return Ok(status),
+            Ok(None) => (),
+            Err(e) => {
+                let depth = frames.len();
+                if let Some(ref mut tracer) = tracer {
+                    tracer.error(&e, state);
+                }
+                let at = catch(e, &mut frames, state)?;
+                if let Some(ref mut tracer) = tracer {
+                    // Everything from the `try` and up is left, even if the
+                    // handler starts at the same depth again
+                    for d in (at..=depth).rev() {
+                        tracer.exit_block(d, state);
+                    }
+                    for d in at..=frames.len() {
+                        tracer.enter_block(d, state);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs commands until the code ends or a command fails, without checking
+/// for a tracer in between
+fn run_steps<W: Write, R: Read>(frames: &mut Vec<Frame>, state: &mut State, io: &mut InOuter<W, R>) -> Result<Option<Status>> {
+    loop {
+        if let Some(status) = step(frames, state, io)? {
+            return Ok(Some(status));
+        }
+    }
+}
+
+/// Like `step`, but tells the tracer about it
+fn traced_step<W: Write, R: Read>(frames: &mut Vec<Frame>, state: &mut State, io: &mut InOuter<W, R>, tracer: &mut dyn Tracer) -> Result<Option<Status>> {
+    let depth = frames.len();
+    // The code of the command about to run, unless a frame is about to end instead
+    let next = frames.last().map(|frame| (frame.code.clone(), frame.pc)).filter(|(code, pc)| *pc < code.len());
+    // The name `:=` and `local` bind, as in `define`
+    let defined = next.as_ref().filter(|(code, pc)| matches!(code[*pc].inner, Command::Define | Command::Local)).and_then(|_| {
+        match state.stack() {
+            [.., Value::Variable(var)] | [.., Value::Variable(var), _] => Some(*var),
+            _ => None,
+        }
+    });
+    if let Some((ref code, pc)) = next {
+        tracer.before_command(&Step::at(code, pc, depth), state);
+    }
+
+    let res = step(frames, state, io)?;
+
+    if let Some((ref code, pc)) = next {
+        tracer.after_command(&Step::at(code, pc, depth), state);
+    }
+    if let Some(var) = defined {
+        tracer.define(&var.name(), state);
+    }
+    for d in (frames.len() + 1..=depth).rev() {
+        tracer.exit_block(d, state);
+    }
+    for d in depth + 1..=frames.len() {
+        tracer.enter_block(d, state);
+    }
+    Ok(res)
+}
+
+/// Runs the next command, giving how the code ended once there is nothing left to run
+fn step<W: Write, R: Read>(frames: &mut Vec<Frame>, state: &mut State, io: &mut InOuter<W, R>) -> Result<Option<Status>> {
+    let depth = frames.len();
+    let frame = match frames.last_mut() {
+        Some(frame) => frame,
+        None => return Ok(Some(Status::Done)),
+    };
+    let cmd = match frame.code.get(frame.pc) {
+        Some(cmd) => cmd,
+        None => {
+            // Going around a loop or repetition counts as a step, so one with
+            // an empty body can't run for ever
+            state.tick(depth).map_err(|e| e.at(frame.span.as_ref()))?;
+            if let FrameKind::Control(ref mut control) = frame.kind {
+                // The control frame has no code of its own, so this is
+                // where it gets to pick the next block to run
+                match control.step(state).map_err(|e| e.at(frame.span.as_ref()))? {
+                    Some((reps, block)) => {
+                        let span = frame.span.clone();
+                        call(frames, state, reps, block, FrameKind::Block, span);
+                    }
+                    None => {
+                        frames.pop();
+                    }
+                }
+                return Ok(None);
+            }
+            frame.reps -= 1;
+            if frame.reps > 0 {
+                frame.pc = 0;
+            } else {
+                frames.pop().unwrap().finish(state)?;
+            }
+            return Ok(None);
+        }
+    };
+    state.tick(depth).map_err(|e| e.at(cmd.span.as_ref()))?;
+    frame.pc += 1;
+
+    let flow = match cmd.inner {
+        Command::Value(ref v) => {
+            state.push(v.clone());
+            return Ok(None);
+        }
+        Command::Quote(len) => {
+            let block = frame.code.slice(frame.pc, frame.pc + len);
+            frame.pc += len;
+            state.push(Value::Block(1, block));
+            return Ok(None);
+        }
+        Command::Capture(len) => {
+            let block = frame.code.slice(frame.pc, frame.pc + len);
+            frame.pc += len;
+            let env = block.variables().into_iter().filter_map(|var| Some((var, state.get_var(var)?.clone()))).collect();
+            state.push(Value::Block(1, block.with_env(env)));
+            return Ok(None);
+        }
+        Command::Collect(len) => {
+            let body = frame.code.slice(frame.pc, frame.pc + len);
+            frame.pc += len;
+            Flow::Collect(body)
+        }
+        ref inner => {
+            state.begin();
+            match crate::exec_command(state, inner, cmd.span.as_ref(), io) {
+                Ok(flow) => flow,
+                Err(e) => {
+                    // Built-in commands don't leave the stack half changed when they fail
+                    state.rollback();
+                    return Err(e.at(cmd.span.as_ref()));
+                }
+            }
+        }
+    };
+    match flow {
+        Flow::Next => (),
+        Flow::Call(reps, block) => {
+            let span = cmd.span.clone();
+            call(frames, state, reps, block, FrameKind::Block, span);
+        }
+        Flow::CallFunction(reps, block) => {
+            let span = cmd.span.clone();
+            call(frames, state, reps, block, FrameKind::Function, span);
+        }
+        Flow::Include(program) => {
+            let span = cmd.span.clone();
+            frames.push(Frame::new(program.code, 1, FrameKind::Include, span));
+        }
+        Flow::Collect(body) => {
+            let span = cmd.span.clone();
+            frames.push(Frame::new(body, 1, FrameKind::Collect(state.stack().len()), span));
+        }
+        Flow::Control(control) => {
+            let span = cmd.span.clone();
+            frames.push(Frame::new(Code::default(), 1, FrameKind::Control(control), span));
+        }
+        Flow::Try { body: (reps, body), handler } => {
+            let span = cmd.span.clone();
+            let kind = FrameKind::Try { handler, depth: state.scope_depth() };
+            frames.push(Frame::new(Code::default(), 1, kind, span.clone()));
+            state.enter_try();
+            call(frames, state, reps, body, FrameKind::Block, span);
+        }
+        Flow::Exit(code) => return Ok(Some(Status::Exit(code))),
+        Flow::Return => loop {
+            // Finish every frame up to and including the innermost function,
+            // or the file being run if there is none
+            let frame = frames.pop().unwrap();
+            let done = matches!(frame.kind, FrameKind::Function | FrameKind::Include | FrameKind::Top);
+            frame.finish(state)?;
+            if done {
+                break;
+            }
+        },
+        flow @ Flow::Break | flow @ Flow::Continue => {
+            let span = cmd.span.clone();
+            // Finish every frame inside the innermost loop
+            loop {
+                match frames.last().unwrap().kind {
+                    FrameKind::Control(ref control) if control.is_loop() => {
+                        if let Flow::Break = flow {
+                            frames.pop();
+                        }
+                        break;
+                    }
+                    FrameKind::Top | FrameKind::Include => return Err(Error::NoLoop.at(span.as_ref())),
+                    _ => frames.pop().unwrap().finish(state)?,
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Gives the error to the handler of the innermost `try`, or adds the include
+/// chain to it if there is none.
+///
+/// Gives the depth of the frame of the `try`, which is where the handler runs.
+fn catch(e: Error, frames: &mut Vec<Frame>, state: &mut State) -> Result<usize> {
+    let i = match frames.iter().rposition(|frame| matches!(frame.kind, FrameKind::Try { .. })) {
+        // Catching these would let programs go on past their limits
+        Some(i) if !matches!(e.kind(), Error::LimitExceeded(_)) => i,
+        _ => return Err(unwind(e, frames)),
+    };
+    frames.truncate(i + 1);
+    let frame = frames.pop().unwrap();
+    if let FrameKind::Try { handler: (reps, handler), depth } = frame.kind {
+        state.fail_try();
+        state.truncate_scopes(depth);
+        state.push(e.value());
+        call(frames, state, reps, handler, FrameKind::Block, frame.span);
+    }
+    Ok(i + 1)
+}
+
+/// Adds the include chain leading to the innermost frame to the error
+fn unwind(mut e: Error, frames: &[Frame]) -> Error {
+    for frame in frames.iter().rev() {
+        if let FrameKind::Include = frame.kind {
+            e = e.included_from(frame.span.as_ref());
+        }
+    }
+    e
+}